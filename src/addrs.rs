@@ -1,24 +1,23 @@
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub const DEFAULT_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 pub const BROADCAST_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255));
 
+// IPv6 has no broadcast, each service gets a link-local multicast group
+pub const DEFAULT_ADDR_V6: Ipv6Addr = Ipv6Addr::UNSPECIFIED;
+pub const DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5752, 0x1);
+pub const ELECTION_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5752, 0x2);
+pub const REPLICATION_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5752, 0x3);
+pub const EXIT_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x5752, 0x4);
+// Magic packets go to every node on the link
+pub const ALL_NODES_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x1);
+
+// Default ports, overridable through the configuration
 pub const WAKEUP_PORT: u16 = 9;
 pub const WAKEUP_SEND_PORT: u16 = 10010;
+pub const SLEEP_SEND_PORT: u16 = 10011;
 pub const DISCOVERY_PORT: u16 = 10000;
 pub const EXIT_PORT: u16 = 12345;
 pub const MONITOR_PORT: u16 = 14321;
 pub const REPLICATION_PORT: u16 = 13333;
 pub const ELECTION_PORT: u16 = 14444;
-
-pub const WAKEUP_ADDR: SocketAddr = SocketAddr::new(BROADCAST_ADDR, WAKEUP_PORT);
-pub const WAKEUP_SEND_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, WAKEUP_SEND_PORT);
-pub const DISCOVERY_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, DISCOVERY_PORT);
-pub const DISCOVERY_BROADCAST_ADDR: SocketAddr = SocketAddr::new(BROADCAST_ADDR, DISCOVERY_PORT);
-pub const EXIT_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, EXIT_PORT);
-pub const EXIT_BROADCAST_ADDR: SocketAddr = SocketAddr::new(BROADCAST_ADDR, EXIT_PORT);
-pub const MONITOR_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, MONITOR_PORT);
-pub const REPLICATION_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, REPLICATION_PORT);
-pub const REPLICATION_BROADCAST_ADDR: SocketAddr = SocketAddr::new(BROADCAST_ADDR, REPLICATION_PORT);
-pub const ELECTION_ADDR: SocketAddr = SocketAddr::new(DEFAULT_ADDR, ELECTION_PORT);
-pub const ELECTION_BROADCAST_ADDR: SocketAddr = SocketAddr::new(BROADCAST_ADDR, ELECTION_PORT);
\ No newline at end of file
@@ -0,0 +1,585 @@
+use crate::{addrs, delays, netif, nodeid::NodeId};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const ENV_PREFIX: &str = "WAKEONRUST_";
+const CONFIG_ENV: &str = "WAKEONRUST_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "wakeonrust.toml";
+const DEFAULT_STATE_FILE: &str = "wakeonrust.state";
+const DEFAULT_NODE_ID_FILE: &str = "wakeonrust.id";
+
+// Every key can be set from the config file, from a WAKEONRUST_<KEY>
+// environment variable or from a --<key> flag, later layers winning.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Nodes only talk to nodes with the same cluster id
+    pub cluster_id: u16,
+    // Shared secret used to authenticate every packet, given either
+    // directly or as a file holding it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<PathBuf>,
+    // How far a packet timestamp may drift from our clock
+    pub auth_window_ms: u64,
+
+    // Network interface to run on, its MAC and address are the ones we
+    // advertise and every socket is bound to it
+    pub interface: Option<String>,
+    // Talk over IPv6 multicast instead of IPv4 broadcasts
+    pub ipv6: bool,
+    // The interface we ended up with, found when loading
+    #[serde(skip)]
+    pub local: netif::Interface,
+    // Send cluster traffic to this administratively scoped IPv4 group
+    // (239.0.0.0/8) instead of broadcasting it
+    pub multicast_group: Option<Ipv4Addr>,
+    pub multicast_ttl: u32,
+    // Address of the interface multicast goes through, the system
+    // picks one when unset
+    pub multicast_interface: Option<Ipv4Addr>,
+
+    // Holds the id this node goes by, created on its first start. Copies
+    // of a machine need their own.
+    pub node_id_file: PathBuf,
+    // Read from node_id_file when the node starts
+    #[serde(skip)]
+    pub node_id: NodeId,
+
+    // Where the replicated table is kept between restarts
+    pub persist_state: bool,
+    pub state_file: PathBuf,
+    // Commands run when the manager asks this machine to sleep, such as
+    // "systemctl suspend", requests are refused when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspend_command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poweroff_command: Option<String>,
+
+    // File that wake results and other notable events are appended to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_log: Option<PathBuf>,
+
+    pub discovery_port: u16,
+    pub monitor_port: u16,
+    pub replication_port: u16,
+    pub election_port: u16,
+    pub exit_port: u16,
+    pub wakeup_port: u16,
+    pub wakeup_send_port: u16,
+    pub sleep_send_port: u16,
+
+    pub input_delay_ms: u64,
+    pub wait_delay_ms: u64,
+    pub check_delay_ms: u64,
+    pub election_delay_ms: u64,
+    pub manager_timeout_ms: u64,
+    // How long an elected manager stays in charge without hearing from a
+    // majority, and how long participants wait before electing a new one
+    pub lease_ms: u64,
+    // A wakeup is resent until the PC comes online or the timeout passes,
+    // waiting twice as long after every try up to the maximum
+    pub wake_retry_ms: u64,
+    pub wake_retry_max_ms: u64,
+    pub wake_timeout_ms: u64,
+    // Least time between waking up two PCs, 0 for no limit
+    pub wake_interval_ms: u64,
+    // How late a scheduled wakeup may still run, after a failover or
+    // with the whole cluster down
+    pub schedule_grace_ms: u64,
+    // How long stopping waits for the subservices to finish
+    pub shutdown_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cluster_id: 0,
+            secret: None,
+            secret_file: None,
+            auth_window_ms: delays::AUTH_WINDOW.as_millis() as u64,
+
+            interface: None,
+            ipv6: false,
+            local: netif::Interface::default(),
+            multicast_group: None,
+            multicast_ttl: 1,
+            multicast_interface: None,
+
+            node_id_file: PathBuf::from(DEFAULT_NODE_ID_FILE),
+            node_id: NodeId::default(),
+
+            persist_state: true,
+            state_file: PathBuf::from(DEFAULT_STATE_FILE),
+            suspend_command: None,
+            poweroff_command: None,
+            event_log: None,
+
+            discovery_port: addrs::DISCOVERY_PORT,
+            monitor_port: addrs::MONITOR_PORT,
+            replication_port: addrs::REPLICATION_PORT,
+            election_port: addrs::ELECTION_PORT,
+            exit_port: addrs::EXIT_PORT,
+            wakeup_port: addrs::WAKEUP_PORT,
+            wakeup_send_port: addrs::WAKEUP_SEND_PORT,
+            sleep_send_port: addrs::SLEEP_SEND_PORT,
+
+            input_delay_ms: delays::INPUT_DELAY.as_millis() as u64,
+            wait_delay_ms: delays::WAIT_DELAY.as_millis() as u64,
+            check_delay_ms: delays::CHECK_DELAY.as_millis() as u64,
+            election_delay_ms: delays::ELECTION_DELAY.as_millis() as u64,
+            manager_timeout_ms: delays::MANAGER_TIMEOUT.as_millis() as u64,
+            lease_ms: delays::LEASE.as_millis() as u64,
+            wake_retry_ms: delays::WAKE_RETRY.as_millis() as u64,
+            wake_retry_max_ms: delays::WAKE_RETRY_MAX.as_millis() as u64,
+            wake_timeout_ms: delays::WAKE_TIMEOUT.as_millis() as u64,
+            wake_interval_ms: delays::WAKE_INTERVAL.as_millis() as u64,
+            schedule_grace_ms: delays::SCHEDULE_GRACE.as_millis() as u64,
+            shutdown_timeout_ms: delays::SHUTDOWN_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), String> {
+        if self.secret.is_some() && self.secret_file.is_some() {
+            return Err("only one of secret and secret_file can be set".to_string());
+        }
+        if self.secret.as_ref().is_some_and(|s| s.is_empty()) {
+            return Err("secret must not be empty".to_string());
+        }
+
+        let listening = [
+            ("discovery_port", self.discovery_port),
+            ("monitor_port", self.monitor_port),
+            ("replication_port", self.replication_port),
+            ("election_port", self.election_port),
+            ("exit_port", self.exit_port),
+            ("wakeup_send_port", self.wakeup_send_port),
+            ("sleep_send_port", self.sleep_send_port),
+        ];
+        for (i, (name, port)) in listening.iter().enumerate() {
+            if *port == 0 {
+                return Err(format!("{} must not be 0", name));
+            }
+            if let Some((other, _)) = listening[..i].iter().find(|(_, p)| p == port) {
+                return Err(format!("{} and {} are both set to {}", other, name, port));
+            }
+        }
+        if self.wakeup_port == 0 {
+            return Err("wakeup_port must not be 0".to_string());
+        }
+
+        if let Some(group) = self.multicast_group {
+            if self.ipv6 {
+                return Err("multicast_group only applies to IPv4".to_string());
+            }
+            if group.octets()[0] != 239 {
+                return Err(format!(
+                    "multicast_group {} is not administratively scoped (239.0.0.0/8)",
+                    group
+                ));
+            }
+        }
+        if self.multicast_ttl == 0 || self.multicast_ttl > 255 {
+            return Err("multicast_ttl must be between 1 and 255".to_string());
+        }
+
+        if self.node_id_file.as_os_str().is_empty() {
+            return Err("node_id_file must not be empty".to_string());
+        }
+        if self.persist_state && self.state_file.as_os_str().is_empty() {
+            return Err("state_file must not be empty".to_string());
+        }
+
+        let delays = [
+            ("input_delay_ms", self.input_delay_ms),
+            ("wait_delay_ms", self.wait_delay_ms),
+            ("check_delay_ms", self.check_delay_ms),
+            ("election_delay_ms", self.election_delay_ms),
+            ("manager_timeout_ms", self.manager_timeout_ms),
+            ("lease_ms", self.lease_ms),
+            ("auth_window_ms", self.auth_window_ms),
+            ("wake_retry_ms", self.wake_retry_ms),
+            ("wake_timeout_ms", self.wake_timeout_ms),
+        ];
+        for (name, delay) in delays {
+            if delay == 0 {
+                return Err(format!("{} must be greater than 0", name));
+            }
+        }
+        // The manager timeout has to outlast a full probe wait, otherwise
+        // participants drop a manager that is still answering
+        if self.manager_timeout_ms <= self.wait_delay_ms.max(self.check_delay_ms) {
+            return Err(
+                "manager_timeout_ms must be greater than wait_delay_ms and check_delay_ms"
+                    .to_string(),
+            );
+        }
+        // The manager renews its lease every third of it, each round
+        // needs at least one read timeout to collect the acks
+        if self.lease_ms < 3 * self.election_delay_ms {
+            return Err("lease_ms must be at least three times election_delay_ms".to_string());
+        }
+        if self.wake_retry_max_ms < self.wake_retry_ms {
+            return Err("wake_retry_max_ms must not be less than wake_retry_ms".to_string());
+        }
+        for (name, command) in [
+            ("suspend_command", &self.suspend_command),
+            ("poweroff_command", &self.poweroff_command),
+        ] {
+            if command.as_ref().is_some_and(|c| c.trim().is_empty()) {
+                return Err(format!("{} must not be empty", name));
+            }
+        }
+        if self
+            .event_log
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            return Err("event_log must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    // Checks the values and finds the network interface to run on, which
+    // every Config needs before a node can start with it
+    pub fn resolve(mut self) -> Result<Config, String> {
+        self.validate()
+            .map_err(|e| format!("Invalid configuration: {}", e))?;
+        self.local = netif::resolve(self.interface.as_deref(), self.ipv6)?;
+        Ok(self)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        if config.secret.is_some() {
+            config.secret = Some("<redacted>".to_string());
+        }
+        toml::to_string_pretty(&config).expect("Failed to serialize config")
+    }
+
+    pub fn secret_key(&self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(secret) = &self.secret {
+            return Ok(Some(secret.as_bytes().to_vec()));
+        }
+        let path: &Path = match &self.secret_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let secret =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let secret = secret.trim_ascii().to_vec();
+        if secret.is_empty() {
+            return Err(format!("{} is empty", path.display()));
+        }
+        Ok(Some(secret))
+    }
+
+    fn any_addr(&self, port: u16) -> SocketAddr {
+        if self.ipv6 {
+            SocketAddr::V6(SocketAddrV6::new(addrs::DEFAULT_ADDR_V6, port, 0, 0))
+        } else {
+            SocketAddr::new(addrs::DEFAULT_ADDR, port)
+        }
+    }
+
+    // The service's group over IPv6, otherwise the multicast group if
+    // there is one or the broadcast address of our subnet
+    fn group_addr(&self, group: Ipv6Addr, port: u16) -> SocketAddr {
+        if self.ipv6 {
+            SocketAddr::V6(SocketAddrV6::new(group, port, 0, self.local.index))
+        } else if let Some(group) = self.multicast_group {
+            SocketAddr::new(IpAddr::V4(group), port)
+        } else {
+            self.broadcast_addr(port)
+        }
+    }
+
+    // The broadcast address of our subnet, or the limited broadcast
+    // address when we don't know it
+    fn broadcast_addr(&self, port: u16) -> SocketAddr {
+        let broadcast = self.local.broadcast.map(IpAddr::V4);
+        SocketAddr::new(broadcast.unwrap_or(addrs::BROADCAST_ADDR), port)
+    }
+
+    // Where to reach a node, link-local addresses need the interface
+    pub fn peer_addr(&self, ip: IpAddr, port: u16) -> SocketAddr {
+        match ip {
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+                SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.local.index))
+            }
+            _ => SocketAddr::new(ip, port),
+        }
+    }
+
+    pub fn discovery_addr(&self) -> SocketAddr {
+        self.any_addr(self.discovery_port)
+    }
+
+    pub fn discovery_broadcast_addr(&self) -> SocketAddr {
+        self.group_addr(addrs::DISCOVERY_GROUP, self.discovery_port)
+    }
+
+    pub fn monitor_addr(&self) -> SocketAddr {
+        self.any_addr(self.monitor_port)
+    }
+
+    pub fn replication_addr(&self) -> SocketAddr {
+        self.any_addr(self.replication_port)
+    }
+
+    pub fn replication_broadcast_addr(&self) -> SocketAddr {
+        self.group_addr(addrs::REPLICATION_GROUP, self.replication_port)
+    }
+
+    pub fn election_addr(&self) -> SocketAddr {
+        self.any_addr(self.election_port)
+    }
+
+    pub fn election_broadcast_addr(&self) -> SocketAddr {
+        self.group_addr(addrs::ELECTION_GROUP, self.election_port)
+    }
+
+    pub fn exit_addr(&self) -> SocketAddr {
+        self.any_addr(self.exit_port)
+    }
+
+    pub fn exit_broadcast_addr(&self) -> SocketAddr {
+        self.group_addr(addrs::EXIT_GROUP, self.exit_port)
+    }
+
+    // Sleeping network cards don't join multicast groups, so over IPv4
+    // magic packets are always broadcast
+    pub fn wakeup_addr(&self) -> SocketAddr {
+        if self.ipv6 {
+            self.group_addr(addrs::ALL_NODES_GROUP, self.wakeup_port)
+        } else {
+            self.broadcast_addr(self.wakeup_port)
+        }
+    }
+
+    pub fn wakeup_send_addr(&self) -> SocketAddr {
+        self.any_addr(self.wakeup_send_port)
+    }
+
+    pub fn sleep_send_addr(&self) -> SocketAddr {
+        self.any_addr(self.sleep_send_port)
+    }
+
+    pub fn input_delay(&self) -> Duration {
+        Duration::from_millis(self.input_delay_ms)
+    }
+
+    pub fn wait_delay(&self) -> Duration {
+        Duration::from_millis(self.wait_delay_ms)
+    }
+
+    pub fn check_delay(&self) -> Duration {
+        Duration::from_millis(self.check_delay_ms)
+    }
+
+    pub fn election_delay(&self) -> Duration {
+        Duration::from_millis(self.election_delay_ms)
+    }
+
+    pub fn manager_timeout(&self) -> Duration {
+        Duration::from_millis(self.manager_timeout_ms)
+    }
+
+    pub fn lease(&self) -> Duration {
+        Duration::from_millis(self.lease_ms)
+    }
+
+    pub fn wake_retry(&self) -> Duration {
+        Duration::from_millis(self.wake_retry_ms)
+    }
+
+    pub fn wake_retry_max(&self) -> Duration {
+        Duration::from_millis(self.wake_retry_max_ms)
+    }
+
+    pub fn wake_timeout(&self) -> Duration {
+        Duration::from_millis(self.wake_timeout_ms)
+    }
+
+    pub fn wake_interval(&self) -> Duration {
+        Duration::from_millis(self.wake_interval_ms)
+    }
+
+    pub fn schedule_grace(&self) -> Duration {
+        Duration::from_millis(self.schedule_grace_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    pub fn auth_window(&self) -> Duration {
+        Duration::from_millis(self.auth_window_ms)
+    }
+}
+
+pub struct Options {
+    pub config: Config,
+    pub print_config: bool,
+    // Asked for with -h or --help, config is left at its defaults then
+    pub help: bool,
+}
+
+// Keys without a default, which TOML leaves out instead of showing empty
+const OPTIONAL_KEYS: &[&str] = &[
+    "secret",
+    "secret_file",
+    "interface",
+    "multicast_group",
+    "multicast_interface",
+    "suspend_command",
+    "poweroff_command",
+    "event_log",
+];
+
+pub fn usage() -> String {
+    let mut keys: Vec<(String, String)> = toml::Table::try_from(Config::default())
+        .expect("Failed to serialize config")
+        .into_iter()
+        .map(|(key, value)| (key, value.to_string()))
+        .collect();
+    for key in OPTIONAL_KEYS {
+        if !keys.iter().any(|(set, _)| set == key) {
+            keys.push((key.to_string(), "(unset)".to_string()));
+        }
+    }
+    keys.sort();
+    let keys = keys
+        .into_iter()
+        .map(|(key, value)| format!("    --{:<24} {}\n", key.replace('_', "-"), value))
+        .collect::<String>();
+    format!(
+        "Usage: wakeonrust [OPTIONS]\n\n\
+        Options:\n    \
+        --config <path>          Read configuration from a TOML file\n    \
+        --print-config           Print the effective configuration and exit\n    \
+        --help                   Print this message and exit\n\n\
+        Configuration (also WAKEONRUST_<KEY> or <key> in the config file), default shown:\n{}",
+        keys
+    )
+}
+
+// Keys holding text, taken as given: a secret like 123456 is no number
+const STRING_KEYS: &[&str] = &[
+    "secret",
+    "secret_file",
+    "interface",
+    "multicast_group",
+    "multicast_interface",
+    "node_id_file",
+    "state_file",
+    "suspend_command",
+    "poweroff_command",
+    "event_log",
+];
+
+// Values coming from the environment or the command line are untyped,
+// so parse them as TOML and fall back to a plain string
+fn parse_value(key: &str, raw: &str) -> toml::Value {
+    if STRING_KEYS.contains(&key) {
+        return toml::Value::String(raw.to_string());
+    }
+    match toml::from_str::<toml::Table>(&format!("value = {}", raw)) {
+        Ok(mut table) => table
+            .remove("value")
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+        Err(_) => toml::Value::String(raw.to_string()),
+    }
+}
+
+fn read_file(path: &PathBuf) -> Result<toml::Table, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+pub fn load() -> Result<Options, String> {
+    let mut config_path = std::env::var(CONFIG_ENV).ok().map(PathBuf::from);
+    let mut print_config = false;
+    let mut overrides = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                return Ok(Options {
+                    config: Config::default(),
+                    print_config,
+                    help: true,
+                });
+            }
+            "--print-config" => print_config = true,
+            _ => {
+                let flag = arg
+                    .strip_prefix("--")
+                    .ok_or_else(|| format!("Unexpected argument '{}'\n\n{}", arg, usage()))?;
+                let (key, value) = match flag.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => {
+                        let value = args
+                            .next()
+                            .ok_or_else(|| format!("Missing value for '{}'", arg))?;
+                        (flag.to_string(), value)
+                    }
+                };
+                if key == "config" {
+                    config_path = Some(PathBuf::from(value));
+                } else {
+                    overrides.push((key.replace('-', "_"), value));
+                }
+            }
+        }
+    }
+
+    // Layer 1: config file, either explicit or the default one if present
+    let mut table = match config_path {
+        Some(path) => read_file(&path)?,
+        None => {
+            let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+            if path.exists() {
+                read_file(&path)?
+            } else {
+                toml::Table::new()
+            }
+        }
+    };
+
+    // Layer 2: environment variables
+    for (name, value) in std::env::vars() {
+        if name == CONFIG_ENV {
+            continue;
+        }
+        if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+            let key = key.to_lowercase();
+            let value = parse_value(&key, &value);
+            table.insert(key, value);
+        }
+    }
+
+    // Layer 3: command line flags
+    for (key, value) in overrides {
+        let value = parse_value(&key, &value);
+        table.insert(key, value);
+    }
+
+    let config = toml::Value::Table(table)
+        .try_into::<Config>()
+        .map_err(|e| format!("Invalid configuration: {}", e))?
+        .resolve()?;
+
+    Ok(Options {
+        config,
+        print_config,
+        help: false,
+    })
+}
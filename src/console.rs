@@ -0,0 +1,411 @@
+pub mod input {
+    use std::collections::BTreeMap;
+    use std::sync::mpsc::{channel, Receiver};
+    use wakeonrust::{
+        packets::{SecureOn, SleepAction},
+        pcinfo::{WakeRequest, WakeSetting, WakeTarget, WakeVia},
+        schedule::{current_minute, format_minute, Schedule, ScheduleEdit},
+        Node, NodeError,
+    };
+
+    fn async_stdin() -> Receiver<String> {
+        let (tx, rx) = channel();
+        std::thread::spawn(move || loop {
+            let mut input = String::new();
+            let bytes = std::io::stdin().read_line(&mut input).unwrap();
+            // If the user presses Ctrl-D, the program will exit
+            if bytes == 0 {
+                input = "exit".to_string();
+            }
+            match tx.send(input.trim().to_lowercase()) {
+                Ok(_) => {}
+                Err(_) => break, // The receiver has been dropped
+            }
+        });
+        rx
+    }
+
+    fn print_replication(node: &Node) {
+        let table_version = node.table_version();
+        println!("Table version {}", table_version);
+        let pcs = node.table().snapshot();
+        for (id, acked) in node.replication_acks() {
+            let name = match pcs.get(&id) {
+                Some(pc_info) => pc_info.get_hostname().clone(),
+                None => id.short(),
+            };
+            println!(
+                "{:<20} acked {:<10} lag {}",
+                name,
+                acked,
+                table_version.saturating_sub(acked)
+            );
+        }
+    }
+
+    // target <hostname> local [port]
+    // target <hostname> directed <broadcast address> [port]
+    // target <hostname> relay <address> [port]
+    // target <hostname> ethernet [port]
+    fn parse_target(args: &[&str]) -> Result<WakeTarget, String> {
+        let (via, rest) = match args {
+            ["local", rest @ ..] => (WakeVia::Local, rest),
+            ["ethernet", rest @ ..] => (WakeVia::Ethernet, rest),
+            ["directed", ip, rest @ ..] => (
+                WakeVia::Directed(
+                    ip.parse()
+                        .map_err(|_| format!("Invalid IPv4 address '{}'", ip))?,
+                ),
+                rest,
+            ),
+            ["relay", ip, rest @ ..] => (
+                WakeVia::Relay(
+                    ip.parse()
+                        .map_err(|_| format!("Invalid IP address '{}'", ip))?,
+                ),
+                rest,
+            ),
+            _ => {
+                return Err(
+                    "Usage: target <hostname> local|directed <ip>|relay <ip>|ethernet [port]"
+                        .to_string(),
+                )
+            }
+        };
+        let port = match rest {
+            [] => None,
+            [port] => match port.parse() {
+                Ok(0) | Err(_) => return Err(format!("Invalid port '{}'", port)),
+                Ok(port) => Some(port),
+            },
+            _ => return Err("Too many arguments".to_string()),
+        };
+        Ok(WakeTarget { via, port })
+    }
+
+    fn print_groups(node: &Node) {
+        let mut groups = BTreeMap::<&str, Vec<&str>>::new();
+        let pcs = node.table().snapshot();
+        for pc_info in pcs.iter() {
+            for group in pc_info.get_groups() {
+                groups
+                    .entry(group)
+                    .or_default()
+                    .push(pc_info.get_hostname());
+            }
+        }
+        if groups.is_empty() {
+            println!("No groups");
+        }
+        for (group, mut hostnames) in groups {
+            hostnames.sort();
+            println!("{:<20} {}", group, hostnames.join(" "));
+        }
+    }
+
+    // <hostname>
+    // all [--except <hostname>...]
+    // group <name> [--except <hostname>...]
+    fn parse_request(args: &[&str]) -> Result<WakeRequest, String> {
+        let parse_except = |args: &[&str]| match args {
+            [] => Ok(Vec::new()),
+            ["--except", hostnames @ ..] if !hostnames.is_empty() => Ok(hostnames
+                .iter()
+                .map(|hostname| hostname.to_string())
+                .collect()),
+            _ => Err(format!("Unexpected '{}'", args.join(" "))),
+        };
+        match args {
+            ["all", rest @ ..] => Ok(WakeRequest::All {
+                except: parse_except(rest)?,
+            }),
+            ["group", name, rest @ ..] => Ok(WakeRequest::Group {
+                name: name.to_string(),
+                except: parse_except(rest)?,
+            }),
+            [hostname] => Ok(WakeRequest::Host(hostname.to_string())),
+            _ => Err(
+                "Expected <hostname>, all or group <name>, then [--except <hostname>...]"
+                    .to_string(),
+            ),
+        }
+    }
+
+    // schedule add <name> <minute> <hour> <day> <month> <weekday> <what to wake>
+    fn parse_schedule(args: &[&str]) -> Result<Schedule, String> {
+        let [name, minute, hour, day, month, weekday, rest @ ..] = args else {
+            return Err(
+                "Usage: schedule add <name> <minute> <hour> <day> <month> <weekday> \
+                <hostname>|all|group <name> [--except <hostname>...]"
+                    .to_string(),
+            );
+        };
+        Ok(Schedule {
+            name: name.to_string(),
+            cron: [*minute, hour, day, month, weekday].join(" ").parse()?,
+            request: parse_request(rest)?,
+            last_run: 0,
+        })
+    }
+
+    fn print_schedules(node: &Node) {
+        let mut schedules = node.schedules();
+        if schedules.is_empty() {
+            println!("No schedules");
+        }
+        let now = current_minute();
+        schedules.sort_by(|a, b| a.name.cmp(&b.name));
+        for schedule in schedules {
+            let next_run = match schedule.next_run(now) {
+                Some(minute) => format_minute(minute),
+                None => "-".to_string(),
+            };
+            println!(
+                "{:<20} {:<20} next {:<16} wake {}",
+                schedule.name,
+                schedule.cron.to_string(),
+                next_run,
+                schedule.request
+            );
+        }
+    }
+
+    fn print_health(node: &Node) {
+        for (name, health) in node.health() {
+            println!("{:<20} {}", name, health);
+        }
+    }
+
+    // Tells the user about a request the node turned down
+    fn check(result: Result<(), NodeError>) {
+        if let Err(e) = result {
+            println!("{}", e);
+        }
+    }
+
+    pub fn start(node: &Node) {
+        let stdin = async_stdin();
+        while node.running() {
+            // Times out to notice the node stopping
+            let Ok(input) = stdin.recv_timeout(node.config().input_delay()) else {
+                continue;
+            };
+            let args = input.split_whitespace().collect::<Vec<&str>>();
+
+            match args.as_slice() {
+                ["exit"] => {
+                    node.stop();
+                }
+                ["wakeup", ..] if !node.is_manager() => {
+                    println!("Only the manager can send wakeups");
+                }
+                ["wakeup", rest @ ..] => match parse_request(rest) {
+                    Ok(request) => check(node.wakeup(request)),
+                    Err(e) => println!("{}", e),
+                },
+                // sleep <hostname> [suspend|poweroff]
+                ["sleep", hostname, action @ ..] => {
+                    if !node.is_manager() {
+                        println!("Only the manager can put PCs to sleep");
+                        continue;
+                    }
+                    let action = match action {
+                        [] | ["suspend"] => SleepAction::Suspend,
+                        ["poweroff"] => SleepAction::PowerOff,
+                        _ => {
+                            println!("Usage: sleep <hostname> [suspend|poweroff]");
+                            continue;
+                        }
+                    };
+                    check(node.sleep(hostname, action));
+                }
+                // group <name> add|remove <hostname>...
+                ["group", name, action @ ("add" | "remove"), hostnames @ ..]
+                    if !hostnames.is_empty() =>
+                {
+                    if !node.is_manager() {
+                        println!("Only the manager can change groups");
+                        continue;
+                    }
+                    for hostname in hostnames {
+                        let setting = if *action == "add" {
+                            WakeSetting::JoinGroup(name.to_string())
+                        } else {
+                            WakeSetting::LeaveGroup(name.to_string())
+                        };
+                        match node.set_wake_setting(hostname, setting) {
+                            // The same for every hostname
+                            Err(e @ NodeError::InvalidName(_)) => {
+                                println!("{}", e);
+                                break;
+                            }
+                            result => check(result),
+                        }
+                    }
+                }
+                ["schedule", "add", rest @ ..] => {
+                    if !node.is_manager() {
+                        println!("Only the manager can change schedules");
+                        continue;
+                    }
+                    match parse_schedule(rest) {
+                        Ok(schedule) => check(node.edit_schedule(ScheduleEdit::Add(schedule))),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                ["schedule", "remove", name] => {
+                    if node.is_manager() {
+                        check(node.edit_schedule(ScheduleEdit::Remove(name.to_string())));
+                    } else {
+                        println!("Only the manager can change schedules");
+                    }
+                }
+                ["schedules"] => {
+                    print_schedules(node);
+                }
+                ["groups"] => {
+                    if node.is_manager() {
+                        print_groups(node);
+                    } else {
+                        println!("Only the manager lists groups");
+                    }
+                }
+                ["target", hostname, rest @ ..] => {
+                    if !node.is_manager() {
+                        println!("Only the manager can change wake targets");
+                        continue;
+                    }
+                    match parse_target(rest) {
+                        Ok(wake_target) => {
+                            check(node.set_wake_setting(hostname, WakeSetting::Target(wake_target)))
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                // password <hostname> <aa:bb:cc:dd:ee:ff|a.b.c.d|none>
+                ["password", hostname, password] => {
+                    if !node.is_manager() {
+                        println!("Only the manager can change passwords");
+                        continue;
+                    }
+                    let secure_on = match *password {
+                        "none" => Ok(None),
+                        password => password.parse::<SecureOn>().map(Some),
+                    };
+                    match secure_on {
+                        Ok(secure_on) => {
+                            check(node.set_wake_setting(hostname, WakeSetting::SecureOn(secure_on)))
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                ["health"] => {
+                    print_health(node);
+                }
+                ["replication"] => {
+                    if node.is_manager() {
+                        print_replication(node);
+                    } else {
+                        println!("Only the manager tracks replication");
+                    }
+                }
+                _ => {
+                    println!("Command not found");
+                }
+            }
+        }
+    }
+}
+
+pub mod output {
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    use wakeonrust::{pcinfo::PCInfo, pctable::PcSnapshot, Event, Node};
+
+    const IP_WIDTH: usize = 17;
+
+    fn make_entry(
+        name: &str,
+        id: &str,
+        mac: &str,
+        ip: &str,
+        ip_width: usize,
+        status: &str,
+    ) -> String {
+        format!(
+            "{:<20} {:<9} {:<21} {:<ip_width$} {:<8}\n",
+            name, id, mac, ip, status
+        )
+    }
+
+    fn make_header(is_manager: bool, ip_width: usize) -> String {
+        make_entry(
+            if is_manager { "Hostname *" } else { "Hostname" },
+            "Id",
+            "MAC Address",
+            "IP Address",
+            ip_width,
+            "Status",
+        )
+    }
+
+    fn entry_to_string(pc_info: &PCInfo, ip_width: usize) -> String {
+        let hostname = if *pc_info.get_is_manager() {
+            format!("{} *", pc_info.get_name())
+        } else {
+            pc_info.get_name().to_string()
+        };
+        make_entry(
+            &hostname,
+            &pc_info.get_id().short(),
+            &pc_info.get_mac().to_string(),
+            &pc_info.get_ip().to_string(),
+            ip_width,
+            &format!("{:?}", pc_info.get_status()),
+        )
+    }
+
+    fn make_table(pcs: &PcSnapshot, is_manager: bool) -> String {
+        // IPv6 addresses need a wider column
+        let ip_width = pcs
+            .iter()
+            .map(|pc_info| pc_info.get_ip().to_string().len())
+            .fold(IP_WIDTH, usize::max);
+        let mut table = make_header(is_manager, ip_width);
+        for pc_info in pcs.iter() {
+            table.push_str(&entry_to_string(pc_info, ip_width));
+        }
+        table
+    }
+
+    // Takes the events from the node's start on, so that its first reports
+    // are shown too
+    pub fn start(node: &Node, mut events: Receiver<Event>) {
+        while node.running() {
+            #[cfg(not(debug_assertions))]
+            clearscreen::clear().unwrap();
+            println!(
+                "{}",
+                make_table(&node.table().snapshot(), node.is_manager())
+            );
+
+            // Drawn again once the table changed, however many times
+            while node.running() {
+                match events.recv_timeout(node.config().wait_delay()) {
+                    Ok(Event::Report(report)) => println!("{}", report),
+                    Ok(event) if event.changes_table() => break,
+                    // Fell behind, what was missed shows in the table
+                    Err(RecvTimeoutError::Disconnected) => {
+                        events = node.subscribe();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            for event in events.try_iter() {
+                if let Event::Report(report) = event {
+                    println!("{}", report);
+                }
+            }
+        }
+    }
+}
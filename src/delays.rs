@@ -1,8 +1,18 @@
 use std::time::Duration;
 
+// Default delays, overridable through the configuration
 // pub const FLUSH_DELAY: Duration = Duration::from_millis(1);
 pub const INPUT_DELAY: Duration = Duration::from_millis(50);
 pub const WAIT_DELAY: Duration = Duration::from_millis(100);
 pub const CHECK_DELAY: Duration = Duration::from_millis(100);
 pub const ELECTION_DELAY: Duration = Duration::from_millis(50);
-pub const MANAGER_TIMEOUT: Duration = Duration::from_millis(500);
\ No newline at end of file
+pub const MANAGER_TIMEOUT: Duration = Duration::from_millis(500);
+pub const LEASE: Duration = Duration::from_millis(1000);
+pub const AUTH_WINDOW: Duration = Duration::from_secs(30);
+pub const WAKE_RETRY: Duration = Duration::from_secs(5);
+pub const WAKE_RETRY_MAX: Duration = Duration::from_secs(60);
+pub const WAKE_TIMEOUT: Duration = Duration::from_secs(300);
+pub const WAKE_INTERVAL: Duration = Duration::from_secs(1);
+pub const SCHEDULE_GRACE: Duration = Duration::from_secs(300);
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+pub const RESTART_DELAY: Duration = Duration::from_secs(1);
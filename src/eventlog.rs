@@ -0,0 +1,33 @@
+use crate::config::Config;
+use crate::events::Event;
+use crate::signals::Signals;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Appends an event to the event log, when one is configured, with the
+// seconds since the epoch in front
+fn record(config: &Config, event: &str) -> std::io::Result<()> {
+    let Some(path) = &config.event_log else {
+        return Ok(());
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", timestamp, event))
+}
+
+// Tells the subscribers and keeps it in the event log, showing it to the
+// user is up to them
+pub fn report(signals: &Signals, config: &Config, event: &str) {
+    if let (Err(e), Some(path)) = (record(config, event), &config.event_log) {
+        let failure = format!("Failed to write to {}: {}", path.display(), e);
+        signals.publish(Event::Report(failure));
+    }
+    signals.publish(Event::Report(event.to_string()));
+}
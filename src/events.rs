@@ -0,0 +1,81 @@
+use crate::nodeid::NodeId;
+use crate::pcinfo::{PCInfo, PCStatus, WakeTarget};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+
+// What a node tells whoever subscribed to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // A PC joined the table, or was found again with new details
+    PcAdded(PCInfo),
+    PcRemoved(PCInfo),
+    // The wake settings of a PC were changed on the manager
+    PcChanged(PCInfo),
+    StatusChanged {
+        id: NodeId,
+        hostname: String,
+        status: PCStatus,
+    },
+    // The manager we follow, our own id when it's us and None when we
+    // stopped managing
+    ManagerChanged(Option<NodeId>),
+    ElectionStarted {
+        term: u64,
+    },
+    ElectionEnded {
+        term: u64,
+    },
+    // A magic packet went out, the first attempt is 1
+    WakeSent {
+        hostname: String,
+        target: WakeTarget,
+        attempt: u32,
+    },
+    // A participant caught up with the manager's table
+    ReplicationApplied {
+        table_version: u32,
+    },
+    // Something worth telling the user, as written to the event log
+    Report(String),
+}
+
+impl Event {
+    // Whether the table as the console shows it changed
+    pub fn changes_table(&self) -> bool {
+        matches!(
+            self,
+            Event::PcAdded(_)
+                | Event::PcRemoved(_)
+                | Event::StatusChanged { .. }
+                | Event::ManagerChanged(_)
+        )
+    }
+}
+
+// How many events a subscriber may fall behind by
+pub const QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers(Mutex<Vec<SyncSender<Event>>>);
+
+impl Subscribers {
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        self.add(tx);
+        rx
+    }
+
+    pub fn add(&self, tx: SyncSender<Event>) {
+        self.0.lock().unwrap().push(tx);
+    }
+
+    // Every subscriber gets its own copy. The ones that went away or fell
+    // too far behind are forgotten, rather than holding the node up, and
+    // find their receiver disconnected.
+    pub fn publish(&self, event: Event) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.try_send(event.clone()).is_ok());
+    }
+}
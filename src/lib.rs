@@ -0,0 +1,26 @@
+// wakeonrust as a library: the cluster protocol, the PC table, sending
+// magic packets, and a Node that runs a member of the cluster
+pub mod config;
+pub mod events;
+pub mod netif;
+pub mod node;
+pub mod nodeid;
+pub mod packets;
+pub mod pcinfo;
+pub mod pctable;
+pub mod schedule;
+pub mod wol;
+
+mod addrs;
+mod delays;
+mod eventlog;
+mod signals;
+mod state;
+mod subservices;
+mod supervisor;
+
+pub use config::Config;
+pub use events::Event;
+pub use node::{Node, NodeError};
+pub use nodeid::NodeId;
+pub use supervisor::Health;
@@ -0,0 +1,315 @@
+use crate::config::Config;
+use mac_address::MacAddress;
+use std::ffi::{CStr, CString};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::fd::{FromRawFd, OwnedFd};
+
+// What we found out about the network interface we run on
+#[derive(Debug, Clone)]
+pub struct Interface {
+    // Only set when chosen with the interface option, sockets are
+    // then bound to it
+    pub name: Option<String>,
+    // 0 when we let the system pick
+    pub index: u32,
+    pub mac: MacAddress,
+    pub ip: IpAddr,
+    // The subnet broadcast address, if we know the subnet
+    pub broadcast: Option<Ipv4Addr>,
+}
+
+impl Default for Interface {
+    fn default() -> Self {
+        Self {
+            name: None,
+            index: 0,
+            mac: MacAddress::default(),
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            broadcast: None,
+        }
+    }
+}
+
+struct Address {
+    name: String,
+    ip: IpAddr,
+    broadcast: Option<Ipv4Addr>,
+}
+
+// Linux names the broadcast address field after the union it shares with
+// the point-to-point destination, the BSDs after the latter
+#[cfg(target_os = "linux")]
+fn broadcast_addr(ifaddr: &libc::ifaddrs) -> *mut libc::sockaddr {
+    ifaddr.ifa_ifu
+}
+
+#[cfg(not(target_os = "linux"))]
+fn broadcast_addr(ifaddr: &libc::ifaddrs) -> *mut libc::sockaddr {
+    ifaddr.ifa_dstaddr
+}
+
+fn addresses() -> Result<Vec<Address>, String> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(format!(
+            "Failed to list network interfaces: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let mut addresses = Vec::new();
+    let mut current = ifaddrs;
+    while let Some(ifaddr) = unsafe { current.as_ref() } {
+        current = ifaddr.ifa_next;
+        let Some(addr) = (unsafe { ifaddr.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        let name = unsafe { CStr::from_ptr(ifaddr.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let ip = match addr.sa_family as i32 {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let broadcast = (ifaddr.ifa_flags & libc::IFF_BROADCAST as u32 != 0)
+            .then_some(broadcast_addr(ifaddr))
+            .filter(|broadcast| !broadcast.is_null())
+            .and_then(|broadcast| {
+                let broadcast = unsafe { &*(broadcast as *const libc::sockaddr_in) };
+                (broadcast.sin_family as i32 == libc::AF_INET)
+                    .then(|| Ipv4Addr::from(u32::from_be(broadcast.sin_addr.s_addr)))
+            });
+        addresses.push(Address {
+            name,
+            ip,
+            broadcast,
+        });
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addresses)
+}
+
+fn index(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|e| e.to_string())?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(format!("Failed to get the index of interface {}", name)),
+        index => Ok(index),
+    }
+}
+
+// Picks the address we advertise: a global one if there is any, since
+// link-local IPv6 addresses are only usable along with the interface
+fn pick_address(addresses: Vec<Address>, ipv6: bool) -> Option<Address> {
+    let mut candidates = addresses
+        .into_iter()
+        .filter(|address| match address.ip {
+            IpAddr::V4(ip) => !ipv6 && !ip.is_loopback(),
+            IpAddr::V6(ip) => ipv6 && !ip.is_loopback() && !ip.is_multicast(),
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|address| match address.ip {
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+        IpAddr::V4(_) => false,
+    });
+    candidates.into_iter().next()
+}
+
+// Without a name we keep to what the system reports first, but over
+// IPv6 we still need an interface for the link-local groups
+pub fn resolve(name: Option<&str>, ipv6: bool) -> Result<Interface, String> {
+    let Some(name) = name else {
+        if !ipv6 {
+            let ip = local_ip_address::local_ip()
+                .map_err(|e| format!("Failed to get local IP address: {}", e))?;
+            let mac = mac_address::get_mac_address()
+                .map_err(|e| format!("Failed to get MAC address: {}", e))?
+                .ok_or("Failed to get MAC address")?;
+            return Ok(Interface {
+                mac,
+                ip,
+                ..Interface::default()
+            });
+        }
+        let address =
+            pick_address(addresses()?, true).ok_or("No network interface has an IPv6 address")?;
+        let mac = mac_address::mac_address_by_name(&address.name)
+            .map_err(|e| format!("Failed to get MAC address: {}", e))?
+            .unwrap_or_default();
+        return Ok(Interface {
+            name: None,
+            index: index(&address.name)?,
+            mac,
+            ip: address.ip,
+            broadcast: None,
+        });
+    };
+
+    let addresses = addresses()?
+        .into_iter()
+        .filter(|address| address.name == name)
+        .collect();
+    let address = pick_address(addresses, ipv6).ok_or_else(|| {
+        let family = if ipv6 { "IPv6" } else { "IPv4" };
+        format!("Interface {} has no {} address", name, family)
+    })?;
+    let mac = mac_address::mac_address_by_name(name)
+        .map_err(|e| format!("Failed to get the MAC address of {}: {}", name, e))?
+        .ok_or_else(|| format!("Interface {} has no MAC address", name))?;
+    Ok(Interface {
+        name: Some(name.to_string()),
+        index: index(name)?,
+        mac,
+        ip: address.ip,
+        broadcast: address.broadcast,
+    })
+}
+
+// Binds a socket, tied to our interface when one was chosen so that
+// everything we send leaves through it
+pub fn bind(config: &Config, addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    if let Some(name) = &config.local.name {
+        bind_to_device(&socket, name)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, name: &str) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+// SO_BINDTODEVICE is Linux only
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, _name: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Binding sockets to an interface is only supported on Linux",
+    ))
+}
+
+// Lets a socket receive what is sent to one of Config's broadcast
+// addresses, and send there through the right interface
+pub fn join(socket: &UdpSocket, config: &Config, group: SocketAddr) -> std::io::Result<()> {
+    match group {
+        SocketAddr::V6(group) => socket.join_multicast_v6(group.ip(), group.scope_id()),
+        SocketAddr::V4(group) if group.ip().is_multicast() => {
+            let interface = match (config.multicast_interface, config.local.ip) {
+                (Some(interface), _) => interface,
+                (None, IpAddr::V4(ip)) if config.local.name.is_some() => ip,
+                _ => Ipv4Addr::UNSPECIFIED,
+            };
+            socket.join_multicast_v4(group.ip(), &interface)?;
+            socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+            set_multicast_interface(socket, interface)
+        }
+        SocketAddr::V4(_) => socket.set_broadcast(true),
+    }
+}
+
+// The standard library can't pick the interface multicast leaves from
+fn set_multicast_interface(socket: &UdpSocket, interface: Ipv4Addr) -> std::io::Result<()> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(interface).to_be(),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr as *const libc::in_addr as *const libc::c_void,
+            std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+const ETHERTYPE_WOL: u16 = 0x0842;
+
+// The interface index raw frames leave through, the one holding our
+// address when none was chosen
+#[cfg(target_os = "linux")]
+fn ethernet_index(config: &Config) -> std::io::Result<i32> {
+    if config.local.index != 0 {
+        return Ok(config.local.index as i32);
+    }
+    let address = addresses()
+        .map_err(std::io::Error::other)?
+        .into_iter()
+        .find(|address| address.ip == config.local.ip)
+        .ok_or_else(|| std::io::Error::other("No interface holds our address"))?;
+    index(&address.name)
+        .map(|index| index as i32)
+        .map_err(std::io::Error::other)
+}
+
+// Sends a magic packet as a raw Ethernet frame to the PC's MAC address,
+// which needs CAP_NET_RAW
+#[cfg(target_os = "linux")]
+pub fn send_ethernet(config: &Config, mac: &MacAddress, packet: &[u8]) -> std::io::Result<()> {
+    let ifindex = ethernet_index(config)?;
+    // The kernel fills in the Ethernet header of datagram sockets
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = ETHERTYPE_WOL.to_be();
+    addr.sll_ifindex = ifindex;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&mac.bytes());
+    let result = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            packet.as_ptr() as *const libc::c_void,
+            packet.len(),
+            0,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Packet sockets are Linux only
+#[cfg(not(target_os = "linux"))]
+pub fn send_ethernet(_config: &Config, _mac: &MacAddress, _packet: &[u8]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Raw Ethernet frames are only sent on Linux",
+    ))
+}
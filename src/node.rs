@@ -0,0 +1,466 @@
+use crate::{
+    config::Config,
+    events::Event,
+    nodeid::{self, NodeId},
+    packets::{Authenticator, SleepAction},
+    pcinfo::{valid_name, PCInfo, PCStatus, WakeRequest, WakeSetting, MAX_GROUPS},
+    pctable::PcTable,
+    schedule::{Schedule, ScheduleEdit, MAX_SCHEDULE_SIZE},
+    signals::Signals,
+    state::{self, Vote},
+    subservices::{
+        discovery, election, management, monitoring, replication, replication::UpdateType,
+        scheduler,
+    },
+    supervisor::{reclaim, Health, Supervisor},
+};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+
+// Why a node turned a request down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeError {
+    // Only the manager wakes PCs up and changes the table
+    NotManager,
+    Stopped,
+    PcNotFound(String),
+    // Several PCs share the hostname, one of their ids has to be given
+    AmbiguousPc { name: String, ids: Vec<String> },
+    GroupNotFound(String),
+    // Group and schedule names, see pcinfo::valid_name
+    InvalidName(String),
+    TooManyGroups(String),
+    ScheduleExists(String),
+    ScheduleNotFound(String),
+    // Wouldn't fit in a packet
+    ScheduleTooLarge(String),
+}
+
+impl std::fmt::Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeError::NotManager => write!(f, "Only the manager can do that"),
+            NodeError::Stopped => write!(f, "The node has stopped"),
+            NodeError::PcNotFound(name) => write!(f, "PC {} not found", name),
+            NodeError::AmbiguousPc { name, ids } => write!(
+                f,
+                "{} PCs are called {}, pick one of {}",
+                ids.len(),
+                name,
+                ids.join(" ")
+            ),
+            NodeError::GroupNotFound(name) => write!(f, "Group {} not found", name),
+            NodeError::InvalidName(name) => write!(f, "Invalid name '{}'", name),
+            NodeError::TooManyGroups(hostname) => {
+                write!(f, "{} is in {} groups already", hostname, MAX_GROUPS)
+            }
+            NodeError::ScheduleExists(name) => write!(f, "Schedule {} already exists", name),
+            NodeError::ScheduleNotFound(name) => write!(f, "Schedule {} not found", name),
+            NodeError::ScheduleTooLarge(name) => write!(f, "Schedule {} is too large", name),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+// A member of the cluster, running every subservice under a supervisor
+// until stopped
+pub struct Node {
+    signals: Arc<Signals>,
+    config: Arc<Config>,
+    pc_table: Arc<PcTable>,
+    acks: Arc<Mutex<HashMap<NodeId, u32>>>,
+    schedules: Arc<Mutex<HashMap<String, Schedule>>>,
+    wakeups: Sender<WakeRequest>,
+    settings: Sender<(String, WakeSetting)>,
+    schedule_edits: Sender<ScheduleEdit>,
+    sleeps: Sender<(String, SleepAction)>,
+    supervisor: Supervisor,
+}
+
+impl Node {
+    // Joins the cluster with a Config that went through Config::resolve
+    pub fn start(config: Config) -> Result<Node, String> {
+        Node::start_subscribed(config, Vec::new())
+    }
+
+    // Like start, with subscribers that hear everything from the first
+    // event on. They are dropped once they fall a queue's worth behind.
+    pub fn start_subscribed(
+        mut config: Config,
+        subscribers: Vec<SyncSender<Event>>,
+    ) -> Result<Node, String> {
+        config.node_id = nodeid::load_or_create(&config.node_id_file)?;
+        let config = Arc::new(config);
+        let authenticator = Arc::new(Authenticator::new(
+            config.secret_key()?,
+            config.auth_window(),
+        ));
+
+        let signals = Arc::new(Signals::new(false));
+        for tx in subscribers {
+            signals.add_subscriber(tx);
+        }
+
+        // Remember the cluster and our vote from the last run before taking
+        // part in it
+        let (saved_state, saved_vote) = if config.persist_state {
+            (
+                state::load(&config.state_file)?,
+                state::load_vote(&state::vote_path(&config.state_file))?.unwrap_or_default(),
+            )
+        } else {
+            (None, Vote::default())
+        };
+        if let Some(saved_state) = &saved_state {
+            signals.overwrite_table_version(saved_state.table_version);
+        }
+
+        let pc_table = Arc::new(PcTable::new(signals.clone()));
+        let rep_acks = Arc::new(Mutex::new(HashMap::new()));
+        let am_schedules = Arc::new(Mutex::new(HashMap::new()));
+        let (wakeup_tx, wakeup_rx) = channel::<WakeRequest>();
+        let (setting_tx, setting_rx) = channel::<(String, WakeSetting)>();
+        let (new_pc_tx, new_pc_rx) = channel::<PCInfo>();
+        let (remove_pc_tx, remove_pc_rx) = channel::<NodeId>();
+        let (sleep_status_tx, sleep_status_rx) = channel::<(NodeId, PCStatus)>();
+        let (update_tx, update_rx) = channel::<(UpdateType, PCInfo)>();
+        let (deposed_tx, deposed_rx) = channel::<std::net::IpAddr>();
+        let (sleep_tx, sleep_rx) = channel::<(String, SleepAction)>();
+        let (schedule_edit_tx, schedule_edit_rx) = channel::<ScheduleEdit>();
+        let (schedule_update_tx, schedule_update_rx) = channel::<(UpdateType, Schedule)>();
+
+        let mut supervisor = Supervisor::new(signals.clone(), config.clone());
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        // Runs after a crash read the vote they saved since
+        let saved_vote = Mutex::new(Some(saved_vote));
+        supervisor.spawn("election", move || {
+            let saved_vote = reclaim(&saved_vote).take();
+            election::initialize(&sigs, &cfg, &auth, saved_vote, deposed_tx.clone());
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        let pcs = pc_table.clone();
+        let acks = rep_acks.clone();
+        let amsc = am_schedules.clone();
+        let sched_wakeup_tx = wakeup_tx.clone();
+        // Only the first run starts from the saved state
+        let saved_state = Mutex::new(saved_state);
+        let update_rx = Mutex::new(update_rx);
+        let schedule_update_rx = Mutex::new(schedule_update_rx);
+        let deposed_rx = Mutex::new(deposed_rx);
+        supervisor.spawn("replication", move || {
+            replication::initialize(
+                &sigs,
+                &cfg,
+                &auth,
+                &pcs,
+                &acks,
+                &amsc,
+                reclaim(&saved_state).take(),
+                &reclaim(&update_rx),
+                &reclaim(&schedule_update_rx),
+                &reclaim(&deposed_rx),
+                sched_wakeup_tx.clone(),
+            );
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        supervisor.spawn("discovery", move || {
+            discovery::discover(&sigs, &cfg, &auth, new_pc_tx.clone());
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        let pcs = pc_table.clone();
+        supervisor.spawn("status monitor", move || {
+            monitoring::status::status_monitor(&sigs, &cfg, &auth, &pcs, sleep_status_tx.clone());
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        supervisor.spawn("exit monitor", move || {
+            monitoring::exit::exit_monitor(&sigs, &cfg, &auth, remove_pc_tx.clone());
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let pcs = pc_table.clone();
+        let wakeup_rx = Mutex::new(wakeup_rx);
+        supervisor.spawn("wakeup", move || {
+            management::wakeup(&sigs, &cfg, &pcs, &reclaim(&wakeup_rx));
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let auth = authenticator.clone();
+        let pcs = pc_table.clone();
+        let sleep_rx = Mutex::new(sleep_rx);
+        supervisor.spawn("sleep", move || {
+            management::put_to_sleep(&sigs, &cfg, &auth, &pcs, &reclaim(&sleep_rx));
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let pcs = pc_table.clone();
+        let rb_update_tx = update_tx.clone();
+        let new_pc_rx = Mutex::new(new_pc_rx);
+        supervisor.spawn("add pcs", move || {
+            management::add_pcs(
+                &sigs,
+                &cfg,
+                &pcs,
+                &reclaim(&new_pc_rx),
+                rb_update_tx.clone(),
+            );
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let pcs = pc_table.clone();
+        let rb_update_tx = update_tx.clone();
+        let sleep_status_rx = Mutex::new(sleep_status_rx);
+        supervisor.spawn("update statuses", move || {
+            management::update_statuses(
+                &sigs,
+                &cfg,
+                &pcs,
+                &reclaim(&sleep_status_rx),
+                rb_update_tx.clone(),
+            );
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let pcs = pc_table.clone();
+        let rb_update_tx = update_tx.clone();
+        let remove_pc_rx = Mutex::new(remove_pc_rx);
+        supervisor.spawn("remove pcs", move || {
+            management::remove_pcs(
+                &sigs,
+                &cfg,
+                &pcs,
+                &reclaim(&remove_pc_rx),
+                rb_update_tx.clone(),
+            );
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let pcs = pc_table.clone();
+        let rb_update_tx = update_tx.clone();
+        let setting_rx = Mutex::new(setting_rx);
+        supervisor.spawn("wake settings", move || {
+            management::set_wake_settings(
+                &sigs,
+                &cfg,
+                &pcs,
+                &reclaim(&setting_rx),
+                rb_update_tx.clone(),
+            );
+        });
+
+        let sigs = signals.clone();
+        let cfg = config.clone();
+        let amsc = am_schedules.clone();
+        let schedule_edit_rx = Mutex::new(schedule_edit_rx);
+        supervisor.spawn("scheduler", move || {
+            scheduler::run(
+                &sigs,
+                &cfg,
+                &amsc,
+                &reclaim(&schedule_edit_rx),
+                schedule_update_tx.clone(),
+            );
+        });
+
+        Ok(Node {
+            signals,
+            config,
+            pc_table,
+            acks: rep_acks,
+            schedules: am_schedules,
+            wakeups: wakeup_tx,
+            settings: setting_tx,
+            schedule_edits: schedule_edit_tx,
+            sleeps: sleep_tx,
+            supervisor,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn running(&self) -> bool {
+        self.signals.running()
+    }
+
+    // Asks every subservice to stop
+    pub fn stop(&self) {
+        self.signals.exit();
+    }
+
+    // Waits until the node is stopped and its subservices are done, giving
+    // up on them after shutdown_timeout_ms
+    pub fn join(&self) {
+        self.supervisor.join(self.config.shutdown_timeout());
+    }
+
+    // How each subservice is doing
+    pub fn health(&self) -> Vec<(&'static str, Health)> {
+        self.supervisor.health()
+    }
+
+    pub fn is_manager(&self) -> bool {
+        self.signals.is_manager()
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.config.node_id
+    }
+
+    pub fn table_version(&self) -> u32 {
+        self.signals.current_table_version()
+    }
+
+    // The table as this node sees it
+    pub fn table(&self) -> &PcTable {
+        &self.pc_table
+    }
+
+    // Replicated to every node, so the same everywhere
+    pub fn schedules(&self) -> Vec<Schedule> {
+        self.schedules.lock().unwrap().values().cloned().collect()
+    }
+
+    // The table version each participant acknowledged, on the manager
+    pub fn replication_acks(&self) -> HashMap<NodeId, u32> {
+        self.acks.lock().unwrap().clone()
+    }
+
+    // Events from now on, the receiver disconnects if it falls more than
+    // events::QUEUE_SIZE behind
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.signals.subscribe()
+    }
+
+    fn check_manager(&self) -> Result<(), NodeError> {
+        if !self.running() {
+            return Err(NodeError::Stopped);
+        }
+        if !self.is_manager() {
+            return Err(NodeError::NotManager);
+        }
+        Ok(())
+    }
+
+    // Requests are checked against the table here, what they lead to is
+    // published as reports
+    fn ask_manager<T>(&self, tx: &Sender<T>, request: T) -> Result<(), NodeError> {
+        self.check_manager()?;
+        tx.send(request).map_err(|_| NodeError::Stopped)
+    }
+
+    pub fn wakeup(&self, request: WakeRequest) -> Result<(), NodeError> {
+        self.check_manager()?;
+        match &request {
+            WakeRequest::Host(name) => {
+                management::find_pc(&self.pc_table, name)?;
+            }
+            WakeRequest::Group { name, .. } => {
+                let pcs = self.pc_table.snapshot();
+                if !pcs.iter().any(|pc_info| pc_info.in_group(name)) {
+                    return Err(NodeError::GroupNotFound(name.clone()));
+                }
+            }
+            WakeRequest::All { .. } => {}
+        }
+        self.ask_manager(&self.wakeups, request)
+    }
+
+    // PCs are named by hostname, or by id when several share it
+    pub fn set_wake_setting(&self, name: &str, setting: WakeSetting) -> Result<(), NodeError> {
+        self.check_manager()?;
+        let pc_info = management::find_pc(&self.pc_table, name)?;
+        match &setting {
+            WakeSetting::JoinGroup(group) | WakeSetting::LeaveGroup(group)
+                if !valid_name(group) =>
+            {
+                return Err(NodeError::InvalidName(group.clone()));
+            }
+            WakeSetting::JoinGroup(group)
+                if !pc_info.in_group(group) && pc_info.get_groups().len() >= MAX_GROUPS =>
+            {
+                return Err(NodeError::TooManyGroups(pc_info.get_hostname().clone()));
+            }
+            _ => {}
+        }
+        self.ask_manager(&self.settings, (name.to_string(), setting))
+    }
+
+    // What a backup would refuse to read back must not be replicated
+    fn check_schedule(schedule: &Schedule) -> Result<(), NodeError> {
+        if !valid_name(&schedule.name) {
+            return Err(NodeError::InvalidName(schedule.name.clone()));
+        }
+        let (group, except) = match &schedule.request {
+            WakeRequest::Host(hostname) => (None, std::slice::from_ref(hostname)),
+            WakeRequest::Group { name, except } => (Some(name), except.as_slice()),
+            WakeRequest::All { except } => (None, except.as_slice()),
+        };
+        if let Some(group) = group.filter(|group| !valid_name(group)) {
+            return Err(NodeError::InvalidName(group.clone()));
+        }
+        // Checked before encoding it, which can't hold more than that
+        let too_large = || NodeError::ScheduleTooLarge(schedule.name.clone());
+        let names = except.iter().map(String::len).sum::<usize>();
+        if except.len() > u8::MAX as usize || names > MAX_SCHEDULE_SIZE {
+            return Err(too_large());
+        }
+        if schedule.to_bytes().len() > MAX_SCHEDULE_SIZE {
+            return Err(too_large());
+        }
+        Ok(())
+    }
+
+    pub fn edit_schedule(&self, edit: ScheduleEdit) -> Result<(), NodeError> {
+        self.check_manager()?;
+        let schedules = self.schedules.lock().unwrap();
+        match &edit {
+            ScheduleEdit::Add(schedule) if schedules.contains_key(&schedule.name) => {
+                return Err(NodeError::ScheduleExists(schedule.name.clone()));
+            }
+            ScheduleEdit::Add(schedule) => Self::check_schedule(schedule)?,
+            ScheduleEdit::Remove(name) if !schedules.contains_key(name) => {
+                return Err(NodeError::ScheduleNotFound(name.clone()));
+            }
+            _ => {}
+        }
+        drop(schedules);
+        self.ask_manager(&self.schedule_edits, edit)
+    }
+
+    pub fn sleep(&self, name: &str, action: SleepAction) -> Result<(), NodeError> {
+        self.check_manager()?;
+        management::find_pc(&self.pc_table, name)?;
+        self.ask_manager(&self.sleeps, (name.to_string(), action))
+    }
+}
+
+// A node nobody holds on to any more shuts down in the background
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use uuid::Uuid;
+
+// What a node is known as in the cluster, generated on its first start and
+// kept in node_id_file, so that PCs sharing a hostname stay apart
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(Uuid);
+
+impl NodeId {
+    pub fn random() -> NodeId {
+        NodeId(uuid::Builder::from_random_bytes(rand::random()).into_uuid())
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> NodeId {
+        NodeId(Uuid::from_bytes(bytes))
+    }
+
+    pub fn bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+
+    // The first 8 digits, enough to tell the PCs of a cluster apart
+    pub fn short(&self) -> String {
+        self.to_string()[..8].to_string()
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.hyphenated())
+    }
+}
+
+impl std::str::FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s.trim())
+            .map(NodeId)
+            .map_err(|_| format!("Invalid node id '{}'", s.trim()))
+    }
+}
+
+// Reads our id, making one up when the file doesn't exist yet
+pub fn load_or_create(path: &Path) -> Result<NodeId, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .parse()
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let id = NodeId::random();
+            std::fs::write(path, format!("{}\n", id))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            Ok(id)
+        }
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
@@ -0,0 +1,320 @@
+use super::{Message, PacketError, BUFFER_SIZE, FLAGS_INDEX, HEADER_SIZE};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const FLAG_AUTHENTICATED: u8 = 0x01;
+const NONCE_SIZE: usize = 8;
+const MAC_SIZE: usize = 32;
+const AUTH_SIZE: usize = NONCE_SIZE + MAC_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// The nonces accepted from one sender within the auth window. Packets
+// read by different subservices arrive in any order, so anything not seen
+// yet goes through, older nonces are refused by their age already.
+#[derive(Default)]
+struct ReplayWindow {
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, nonce: u64, oldest: u64) -> bool {
+        self.seen = self.seen.split_off(&oldest);
+        self.seen.insert(nonce)
+    }
+}
+
+// Signs outgoing packets and checks incoming ones when a shared secret
+// is configured. Nonces are microsecond timestamps, bumped so they are
+// strictly increasing, which lets receivers reject replays even across
+// restarts of the sender.
+pub struct Authenticator {
+    key: Option<Vec<u8>>,
+    max_age: Duration,
+    last_nonce: Mutex<u64>,
+    windows: Mutex<HashMap<IpAddr, ReplayWindow>>,
+}
+
+impl Authenticator {
+    pub fn new(key: Option<Vec<u8>>, max_age: Duration) -> Self {
+        Self {
+            key,
+            max_age,
+            last_nonce: Mutex::new(0),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_keyed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn next_nonce(&self) -> u64 {
+        let mut last_nonce = self.last_nonce.lock().unwrap();
+        *last_nonce = now_micros().max(*last_nonce + 1);
+        *last_nonce
+    }
+
+    fn mac(key: &[u8], data: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+        mac.update(data);
+        mac
+    }
+
+    pub fn seal(&self, packet: &[u8]) -> Vec<u8> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return packet.to_vec(),
+        };
+        let mut sealed = packet.to_vec();
+        sealed[FLAGS_INDEX] |= FLAG_AUTHENTICATED;
+        sealed.extend(self.next_nonce().to_be_bytes());
+        let mac = Self::mac(key, &sealed).finalize().into_bytes();
+        sealed.extend(mac.iter());
+        sealed
+    }
+
+    // Returns the length of the packet without the authentication trailer
+    pub fn open(&self, packet: &[u8], src: IpAddr) -> Result<usize, PacketError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(PacketError::Truncated);
+        }
+        let authenticated = packet[FLAGS_INDEX] & FLAG_AUTHENTICATED != 0;
+        let key = match &self.key {
+            Some(key) if authenticated => key,
+            None if !authenticated => return Ok(packet.len()),
+            _ => return Err(PacketError::Unauthenticated),
+        };
+        if packet.len() < HEADER_SIZE + AUTH_SIZE {
+            return Err(PacketError::Truncated);
+        }
+
+        let mac_start = packet.len() - MAC_SIZE;
+        Self::mac(key, &packet[..mac_start])
+            .verify_slice(&packet[mac_start..])
+            .map_err(|_| PacketError::Unauthenticated)?;
+
+        let nonce_start = mac_start - NONCE_SIZE;
+        let nonce = u64::from_be_bytes(packet[nonce_start..mac_start].try_into().unwrap());
+        let now = now_micros();
+        let max_age = self.max_age.as_micros() as u64;
+        if now.abs_diff(nonce) > max_age {
+            return Err(PacketError::Replayed);
+        }
+        let mut windows = self.windows.lock().unwrap();
+        if !windows
+            .entry(src)
+            .or_default()
+            .accept(nonce, now.saturating_sub(max_age))
+        {
+            return Err(PacketError::Replayed);
+        }
+        Ok(nonce_start)
+    }
+}
+
+// UDP socket speaking in messages: everything sent is encoded for our
+// cluster and sealed, anything received that fails authentication or
+// decoding is silently dropped
+pub struct Socket<'a> {
+    socket: UdpSocket,
+    cluster_id: u16,
+    auth: &'a Authenticator,
+}
+
+impl<'a> Socket<'a> {
+    pub fn new(socket: UdpSocket, cluster_id: u16, auth: &'a Authenticator) -> Self {
+        Self {
+            socket,
+            cluster_id,
+            auth,
+        }
+    }
+
+    pub fn send_to(&self, message: &Message, addr: SocketAddr) -> std::io::Result<usize> {
+        let packet = message.encode(self.cluster_id);
+        self.socket.send_to(&self.auth.seal(&packet), addr)
+    }
+
+    // Waits no longer than the read timeout overall, however many packets
+    // of other clusters get dropped meanwhile
+    pub fn recv_from(&self) -> std::io::Result<(Message, SocketAddr)> {
+        let timeout = self.socket.read_timeout()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let received = self.recv_until(deadline);
+        if deadline.is_some() {
+            self.socket.set_read_timeout(timeout)?;
+        }
+        received
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> std::io::Result<(Message, SocketAddr)> {
+        let mut buf = [0; BUFFER_SIZE];
+        loop {
+            let (amt, src) = self.socket.recv_from(&mut buf)?;
+            let decoded = self
+                .auth
+                .open(&buf[..amt], src.ip())
+                .and_then(|amt| Message::decode(&buf[..amt], self.cluster_id));
+            if let Ok(message) = decoded {
+                return Ok((message, src));
+            }
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+                self.socket.set_read_timeout(Some(left))?;
+            }
+        }
+    }
+}
+
+impl std::ops::Deref for Socket<'_> {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    fn keyed() -> Authenticator {
+        Authenticator::new(Some(b"secret".to_vec()), WINDOW)
+    }
+
+    fn packet() -> Vec<u8> {
+        Message::Status.encode(0)
+    }
+
+    // Seals with a nonce of our choosing, as a sender with another clock
+    // would
+    fn seal_with_nonce(packet: &[u8], nonce: u64) -> Vec<u8> {
+        let mut sealed = packet.to_vec();
+        sealed[FLAGS_INDEX] |= FLAG_AUTHENTICATED;
+        sealed.extend(nonce.to_be_bytes());
+        let mac = Authenticator::mac(b"secret", &sealed)
+            .finalize()
+            .into_bytes();
+        sealed.extend(mac.iter());
+        sealed
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealed = keyed().seal(&packet());
+        assert_eq!(sealed.len(), packet().len() + AUTH_SIZE);
+        assert_eq!(keyed().open(&sealed, SRC), Ok(packet().len()));
+    }
+
+    #[test]
+    fn without_a_key() {
+        let auth = Authenticator::new(None, WINDOW);
+        assert_eq!(auth.seal(&packet()), packet());
+        assert_eq!(auth.open(&packet(), SRC), Ok(packet().len()));
+        let sealed = keyed().seal(&packet());
+        assert_eq!(auth.open(&sealed, SRC), Err(PacketError::Unauthenticated));
+    }
+
+    #[test]
+    fn unsealed_packets_are_refused() {
+        assert_eq!(
+            keyed().open(&packet(), SRC),
+            Err(PacketError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let sealed = keyed().seal(&packet());
+        for index in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 0x10;
+            assert!(keyed().open(&tampered, SRC).is_err(), "byte {}", index);
+        }
+        let other = Authenticator::new(Some(b"other".to_vec()), WINDOW).seal(&packet());
+        assert_eq!(keyed().open(&other, SRC), Err(PacketError::Unauthenticated));
+    }
+
+    #[test]
+    fn truncated_trailer() {
+        let sealed = keyed().seal(&packet());
+        assert_eq!(
+            keyed().open(&sealed[..HEADER_SIZE + AUTH_SIZE - 1], SRC),
+            Err(PacketError::Truncated)
+        );
+        assert_eq!(
+            keyed().open(&sealed[..HEADER_SIZE - 1], SRC),
+            Err(PacketError::Truncated)
+        );
+    }
+
+    #[test]
+    fn replayed_nonce() {
+        let auth = keyed();
+        let sealed = keyed().seal(&packet());
+        assert!(auth.open(&sealed, SRC).is_ok());
+        assert_eq!(auth.open(&sealed, SRC), Err(PacketError::Replayed));
+        // Each sender has its own nonces
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!(auth.open(&sealed, other).is_ok());
+    }
+
+    #[test]
+    fn nonces_out_of_order() {
+        let sender = keyed();
+        let first = sender.seal(&packet());
+        let second = sender.seal(&packet());
+        let auth = keyed();
+        assert!(auth.open(&second, SRC).is_ok());
+        assert!(auth.open(&first, SRC).is_ok());
+        assert_eq!(auth.open(&first, SRC), Err(PacketError::Replayed));
+    }
+
+    #[test]
+    fn nonces_outside_the_window() {
+        let window = WINDOW.as_micros() as u64;
+        let old = seal_with_nonce(&packet(), now_micros() - 2 * window);
+        assert_eq!(keyed().open(&old, SRC), Err(PacketError::Replayed));
+        let ahead = seal_with_nonce(&packet(), now_micros() + 2 * window);
+        assert_eq!(keyed().open(&ahead, SRC), Err(PacketError::Replayed));
+        let skewed = seal_with_nonce(&packet(), now_micros() - window / 2);
+        assert!(keyed().open(&skewed, SRC).is_ok());
+    }
+
+    #[test]
+    fn nonces_keep_increasing() {
+        let auth = keyed();
+        let nonces = (0..1000).map(|_| auth.next_nonce()).collect::<Vec<_>>();
+        assert!(nonces.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn window_forgets_old_nonces() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(10, 0));
+        assert!(window.accept(20, 0));
+        assert!(!window.accept(10, 0));
+        assert!(window.accept(30, 15));
+        assert_eq!(window.seen.iter().copied().collect::<Vec<_>>(), [20, 30]);
+    }
+}
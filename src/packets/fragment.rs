@@ -0,0 +1,323 @@
+use super::{Message, PacketError, Reader};
+use crate::pcinfo::PCInfo;
+use crate::schedule::Schedule;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Leaves room in BUFFER_SIZE for the header, the fragment fields
+// and the authentication trailer
+pub const FRAGMENT_SIZE: usize = 900;
+
+// Everything the replicated table holds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub pcs: Vec<PCInfo>,
+    pub schedules: Vec<Schedule>,
+}
+
+pub fn encode_snapshot(snapshot: &Snapshot) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend((snapshot.pcs.len() as u32).to_be_bytes());
+    for pc_info in &snapshot.pcs {
+        buf.extend(pc_info.to_bytes());
+    }
+    buf.extend((snapshot.schedules.len() as u32).to_be_bytes());
+    for schedule in &snapshot.schedules {
+        buf.extend(schedule.to_bytes());
+    }
+    buf
+}
+
+pub fn decode_snapshot(bytes: &[u8]) -> Result<Snapshot, PacketError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u32()?;
+    let pcs = (0..count)
+        .map(|_| PCInfo::read(&mut reader))
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = reader.u32()?;
+    let schedules = (0..count)
+        .map(|_| Schedule::read(&mut reader))
+        .collect::<Result<Vec<_>, _>>()?;
+    if !reader.is_empty() {
+        return Err(PacketError::InvalidPayload("trailing bytes in snapshot"));
+    }
+    Ok(Snapshot { pcs, schedules })
+}
+
+// Splits a table snapshot into as many replication messages as needed
+pub fn fragment(table_version: u32, snapshot: &Snapshot) -> Vec<Message> {
+    let snapshot = encode_snapshot(snapshot);
+    let checksum = crc32fast::hash(&snapshot);
+    let chunks = snapshot.chunks(FRAGMENT_SIZE).collect::<Vec<_>>();
+    let count = chunks.len() as u16;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| Message::Replication {
+            table_version,
+            index: index as u16,
+            count,
+            checksum,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+struct Partial {
+    table_version: u32,
+    checksum: u32,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+// Collects replication fragments per sender until a whole snapshot is in.
+// A fragment of a newer table version discards whatever was pending.
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<IpAddr, Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the table version and its contents once the last missing
+    // fragment arrives
+    pub fn add(&mut self, src: IpAddr, message: Message) -> Option<(u32, Snapshot)> {
+        let (table_version, index, count, checksum, data) = match message {
+            Message::Replication {
+                table_version,
+                index,
+                count,
+                checksum,
+                data,
+            } => (
+                table_version,
+                index as usize,
+                count as usize,
+                checksum,
+                data,
+            ),
+            _ => return None,
+        };
+        if index >= count {
+            return None;
+        }
+
+        let partial = self.pending.entry(src).or_insert_with(|| Partial {
+            table_version,
+            checksum,
+            fragments: vec![None; count],
+            missing: count,
+        });
+        if partial.table_version != table_version
+            || partial.checksum != checksum
+            || partial.fragments.len() != count
+        {
+            if table_version < partial.table_version {
+                return None; // Late fragment of an older snapshot
+            }
+            *partial = Partial {
+                table_version,
+                checksum,
+                fragments: vec![None; count],
+                missing: count,
+            };
+        }
+        if partial.fragments[index].is_none() {
+            partial.fragments[index] = Some(data);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+
+        let partial = self.pending.remove(&src)?;
+        let snapshot = partial
+            .fragments
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect::<Vec<_>>();
+        if crc32fast::hash(&snapshot) != partial.checksum {
+            return None;
+        }
+        let snapshot = decode_snapshot(&snapshot).ok()?;
+        Some((partial.table_version, snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nodeid::NodeId;
+    use crate::packets::BUFFER_SIZE;
+    use crate::pcinfo::{PCStatus, WakeRequest};
+    use mac_address::MacAddress;
+    use std::net::Ipv4Addr;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn snapshot(pcs: usize) -> Snapshot {
+        Snapshot {
+            pcs: (0..pcs)
+                .map(|i| {
+                    PCInfo::new(
+                        NodeId::random(),
+                        format!("lab-{}", i),
+                        MacAddress::new([0x02, 0, 0, 0, (i >> 8) as u8, i as u8]),
+                        IpAddr::V4(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8)),
+                        PCStatus::Online,
+                        i == 0,
+                    )
+                })
+                .collect(),
+            schedules: vec![Schedule {
+                name: "nightly".to_string(),
+                cron: "0 2 * * *".parse().unwrap(),
+                request: WakeRequest::All { except: Vec::new() },
+                last_run: 0,
+            }],
+        }
+    }
+
+    // Big enough to need several fragments
+    fn fragments(table_version: u32) -> (Snapshot, Vec<Message>) {
+        let snapshot = snapshot(100);
+        let fragments = fragment(table_version, &snapshot);
+        assert!(fragments.len() > 2);
+        (snapshot, fragments)
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        for snapshot in [Snapshot::default(), snapshot(3)] {
+            assert_eq!(decode_snapshot(&encode_snapshot(&snapshot)), Ok(snapshot));
+        }
+    }
+
+    #[test]
+    fn truncated_snapshot() {
+        let encoded = encode_snapshot(&snapshot(3));
+        for len in 0..encoded.len() {
+            assert!(decode_snapshot(&encoded[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn trailing_snapshot() {
+        let mut encoded = encode_snapshot(&snapshot(3));
+        encoded.push(0);
+        assert_eq!(
+            decode_snapshot(&encoded),
+            Err(PacketError::InvalidPayload("trailing bytes in snapshot"))
+        );
+    }
+
+    #[test]
+    fn fragments_fit_a_packet() {
+        let (_, fragments) = fragments(1);
+        for message in fragments {
+            assert!(message.encode(0).len() <= BUFFER_SIZE);
+        }
+    }
+
+    #[test]
+    fn in_order() {
+        let (snapshot, fragments) = fragments(1);
+        let last = fragments.len() - 1;
+        let mut reassembler = Reassembler::new();
+        for (index, message) in fragments.into_iter().enumerate() {
+            let result = reassembler.add(SRC, message);
+            if index < last {
+                assert_eq!(result, None);
+            } else {
+                assert_eq!(result, Some((1, snapshot.clone())));
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (snapshot, mut fragments) = fragments(1);
+        fragments.reverse();
+        fragments.swap(0, 1);
+        let mut reassembler = Reassembler::new();
+        let results = fragments
+            .into_iter()
+            .map(|message| reassembler.add(SRC, message))
+            .collect::<Vec<_>>();
+        let (last, rest) = results.split_last().unwrap();
+        assert!(rest.iter().all(Option::is_none));
+        assert_eq!(last, &Some((1, snapshot)));
+    }
+
+    #[test]
+    fn duplicates() {
+        let (snapshot, fragments) = fragments(1);
+        let mut reassembler = Reassembler::new();
+        // Every fragment but the last twice, which must not count as it
+        let (last, rest) = fragments.split_last().unwrap();
+        for message in rest.iter().chain(rest) {
+            assert_eq!(reassembler.add(SRC, message.clone()), None);
+        }
+        assert_eq!(reassembler.add(SRC, last.clone()), Some((1, snapshot)));
+        // A retransmission after that starts over
+        assert_eq!(reassembler.add(SRC, last.clone()), None);
+    }
+
+    #[test]
+    fn senders_are_kept_apart() {
+        let (snapshot, fragments) = fragments(1);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut reassembler = Reassembler::new();
+        let (last, rest) = fragments.split_last().unwrap();
+        for message in rest {
+            assert_eq!(reassembler.add(SRC, message.clone()), None);
+        }
+        assert_eq!(reassembler.add(other, last.clone()), None);
+        assert_eq!(reassembler.add(SRC, last.clone()), Some((1, snapshot)));
+    }
+
+    #[test]
+    fn newer_version_replaces_pending() {
+        let (_, old) = fragments(1);
+        let (snapshot, new) = fragments(2);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.add(SRC, old[0].clone()), None);
+        let (last, rest) = new.split_last().unwrap();
+        for message in rest {
+            assert_eq!(reassembler.add(SRC, message.clone()), None);
+        }
+        // Late fragments of the older version are dropped
+        for message in &old[1..] {
+            assert_eq!(reassembler.add(SRC, message.clone()), None);
+        }
+        assert_eq!(reassembler.add(SRC, last.clone()), Some((2, snapshot)));
+    }
+
+    #[test]
+    fn bad_fragments() {
+        let mut reassembler = Reassembler::new();
+        let out_of_range = Message::Replication {
+            table_version: 1,
+            index: 1,
+            count: 1,
+            checksum: 0,
+            data: Vec::new(),
+        };
+        assert_eq!(reassembler.add(SRC, out_of_range), None);
+        assert_eq!(reassembler.add(SRC, Message::Status), None);
+
+        let data = encode_snapshot(&snapshot(1));
+        let corrupt = Message::Replication {
+            table_version: 1,
+            index: 0,
+            count: 1,
+            checksum: crc32fast::hash(&data) ^ 1,
+            data,
+        };
+        assert_eq!(reassembler.add(SRC, corrupt), None);
+    }
+}
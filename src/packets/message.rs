@@ -0,0 +1,637 @@
+use super::{make_header, parse_header, PacketError, PacketType, PacketType::*};
+use crate::nodeid::NodeId;
+use crate::pcinfo::PCInfo;
+use crate::schedule::Schedule;
+use crate::subservices::replication::UpdateType;
+use mac_address::MacAddress;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// Bounds checked cursor over a payload
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes.get(self.pos..).unwrap_or_default()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], PacketError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(PacketError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, PacketError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, PacketError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, PacketError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // Addresses are prefixed by their family, 4 or 6
+    pub fn ip(&mut self) -> Result<IpAddr, PacketError> {
+        match self.u8()? {
+            4 => Ok(IpAddr::V4(Ipv4Addr::from(
+                <[u8; 4]>::try_from(self.take(4)?).unwrap(),
+            ))),
+            6 => Ok(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(self.take(16)?).unwrap(),
+            ))),
+            _ => Err(PacketError::InvalidPayload("unknown address family")),
+        }
+    }
+
+    pub fn mac(&mut self) -> Result<MacAddress, PacketError> {
+        Ok(MacAddress::new(self.take(6)?.try_into().unwrap()))
+    }
+
+    pub fn node_id(&mut self) -> Result<NodeId, PacketError> {
+        Ok(NodeId::from_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    // Strings are prefixed by their length as a u16
+    pub fn bytes(&mut self) -> Result<Vec<u8>, PacketError> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, PacketError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| PacketError::InvalidPayload("string is not utf-8"))
+    }
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let len = u16::try_from(bytes.len()).expect("field too long for a packet");
+    buf.extend(len.to_be_bytes());
+    buf.extend(bytes);
+}
+
+pub fn write_string(buf: &mut Vec<u8>, string: &str) {
+    write_bytes(buf, string.as_bytes());
+}
+
+pub fn write_ip(buf: &mut Vec<u8>, ip: &IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend(ip.octets());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SleepAction {
+    Suspend = 0x01,
+    PowerOff,
+}
+
+impl std::convert::TryFrom<u8> for SleepAction {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(SleepAction::Suspend),
+            0x02 => Ok(SleepAction::PowerOff),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for SleepAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SleepAction::Suspend => write!(f, "suspend"),
+            SleepAction::PowerOff => write!(f, "power off"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    // Manager asking a participant if it is awake, and its answer
+    Status,
+    StatusAck,
+    // A participant leaving the cluster
+    Exit {
+        id: NodeId,
+    },
+    // A participant looking for the manager, and the manager's answer
+    Discovery {
+        id: NodeId,
+        hostname: String,
+        mac: MacAddress,
+    },
+    DiscoveryAck {
+        id: NodeId,
+        hostname: String,
+        mac: MacAddress,
+    },
+    // One fragment of a full copy of the manager's table,
+    // see packets::fragment
+    Replication {
+        table_version: u32,
+        index: u16,
+        count: u16,
+        checksum: u32,
+        data: Vec<u8>,
+    },
+    // A single change to the manager's table
+    ReplicationDelta {
+        table_version: u32,
+        update_type: UpdateType,
+        pc_info: PCInfo,
+    },
+    // A single change to the manager's schedules, numbered along with
+    // the changes to its table
+    ScheduleDelta {
+        table_version: u32,
+        update_type: UpdateType,
+        schedule: Schedule,
+    },
+    // A backup that missed deltas asking for a full copy
+    SnapshotRequest {
+        table_version: u32,
+    },
+    // A backup telling the manager which table version it holds
+    ReplicationAck {
+        table_version: u32,
+    },
+    // A candidate asking for votes in a new term. A pre-vote only asks
+    // whether it would get them, without anyone taking the term yet.
+    Election {
+        term: u64,
+        candidate: NodeId,
+        table_version: u32,
+        pre_vote: bool,
+    },
+    // The answer to a candidate, also sent to acknowledge the manager
+    Vote {
+        term: u64,
+        granted: bool,
+        pre_vote: bool,
+    },
+    // The manager of a term announcing itself, repeated to renew its lease
+    Heartbeat {
+        term: u64,
+        leader: NodeId,
+        table_version: u32,
+    },
+    // The manager asking a participant to go to sleep, and its answer.
+    // Retries keep the id so the participant acts only once
+    Sleep {
+        id: u32,
+        action: SleepAction,
+    },
+    SleepAck {
+        id: u32,
+        accepted: bool,
+    },
+}
+
+impl Message {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Message::Status => SsrPacket,
+            Message::StatusAck => SsrAckPacket,
+            Message::Exit { .. } => SsePacket,
+            Message::Discovery { .. } => SsdPacket,
+            Message::DiscoveryAck { .. } => SsdAckPacket,
+            Message::Replication { .. } => SsrepPacket,
+            Message::ReplicationDelta { .. } => SsrepDeltaPacket,
+            Message::ScheduleDelta { .. } => SsrepSchedPacket,
+            Message::SnapshotRequest { .. } => SsrepReqPacket,
+            Message::ReplicationAck { .. } => SsrepAckPacket,
+            Message::Election { .. } => SselPacket,
+            Message::Vote { .. } => SselVotePacket,
+            Message::Heartbeat { .. } => SselFinPacket,
+            Message::Sleep { .. } => SsslPacket,
+            Message::SleepAck { .. } => SsslAckPacket,
+        }
+    }
+
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Message::Status | Message::StatusAck => {}
+            Message::Exit { id } => buf.extend(id.bytes()),
+            Message::Discovery { id, hostname, mac }
+            | Message::DiscoveryAck { id, hostname, mac } => {
+                buf.extend(id.bytes());
+                buf.extend(mac.bytes());
+                write_string(&mut buf, hostname);
+            }
+            Message::Replication {
+                table_version,
+                index,
+                count,
+                checksum,
+                data,
+            } => {
+                buf.extend(table_version.to_be_bytes());
+                buf.extend(index.to_be_bytes());
+                buf.extend(count.to_be_bytes());
+                buf.extend(checksum.to_be_bytes());
+                write_bytes(&mut buf, data);
+            }
+            Message::ReplicationDelta {
+                table_version,
+                update_type,
+                pc_info,
+            } => {
+                buf.extend(table_version.to_be_bytes());
+                buf.push(*update_type as u8);
+                buf.extend(pc_info.to_bytes());
+            }
+            Message::ScheduleDelta {
+                table_version,
+                update_type,
+                schedule,
+            } => {
+                buf.extend(table_version.to_be_bytes());
+                buf.push(*update_type as u8);
+                buf.extend(schedule.to_bytes());
+            }
+            Message::SnapshotRequest { table_version }
+            | Message::ReplicationAck { table_version } => buf.extend(table_version.to_be_bytes()),
+            Message::Election {
+                term,
+                candidate,
+                table_version,
+                pre_vote,
+            } => {
+                buf.extend(term.to_be_bytes());
+                buf.extend(candidate.bytes());
+                buf.extend(table_version.to_be_bytes());
+                buf.push(*pre_vote as u8);
+            }
+            Message::Vote {
+                term,
+                granted,
+                pre_vote,
+            } => {
+                buf.extend(term.to_be_bytes());
+                buf.push(*granted as u8);
+                buf.push(*pre_vote as u8);
+            }
+            Message::Heartbeat {
+                term,
+                leader,
+                table_version,
+            } => {
+                buf.extend(term.to_be_bytes());
+                buf.extend(leader.bytes());
+                buf.extend(table_version.to_be_bytes());
+            }
+            Message::Sleep { id, action } => {
+                buf.extend(id.to_be_bytes());
+                buf.push(*action as u8);
+            }
+            Message::SleepAck { id, accepted } => {
+                buf.extend(id.to_be_bytes());
+                buf.push(*accepted as u8);
+            }
+        }
+        buf
+    }
+
+    pub fn encode(&self, cluster_id: u16) -> Vec<u8> {
+        let payload = self.encode_payload();
+        let header = make_header(self.packet_type(), payload.len(), cluster_id);
+        [header.to_vec(), payload].concat()
+    }
+
+    pub fn decode(packet: &[u8], cluster_id: u16) -> Result<Message, PacketError> {
+        let (packet_type, payload) = parse_header(packet, cluster_id)?;
+        let mut reader = Reader::new(payload);
+        let message = match packet_type {
+            SsrPacket => Message::Status,
+            SsrAckPacket => Message::StatusAck,
+            SsePacket => Message::Exit {
+                id: reader.node_id()?,
+            },
+            SsdPacket | SsdAckPacket => {
+                let id = reader.node_id()?;
+                let mac = reader.mac()?;
+                let hostname = reader.string()?;
+                if packet_type == SsdPacket {
+                    Message::Discovery { id, hostname, mac }
+                } else {
+                    Message::DiscoveryAck { id, hostname, mac }
+                }
+            }
+            SsrepPacket => Message::Replication {
+                table_version: reader.u32()?,
+                index: reader.u16()?,
+                count: reader.u16()?,
+                checksum: reader.u32()?,
+                data: reader.bytes()?,
+            },
+            SsrepDeltaPacket => Message::ReplicationDelta {
+                table_version: reader.u32()?,
+                update_type: UpdateType::try_from(reader.u8()?)
+                    .map_err(|_| PacketError::InvalidPayload("unknown update type"))?,
+                pc_info: PCInfo::read(&mut reader)?,
+            },
+            SsrepSchedPacket => Message::ScheduleDelta {
+                table_version: reader.u32()?,
+                update_type: UpdateType::try_from(reader.u8()?)
+                    .map_err(|_| PacketError::InvalidPayload("unknown update type"))?,
+                schedule: Schedule::read(&mut reader)?,
+            },
+            SsrepReqPacket => Message::SnapshotRequest {
+                table_version: reader.u32()?,
+            },
+            SsrepAckPacket => Message::ReplicationAck {
+                table_version: reader.u32()?,
+            },
+            SselPacket => Message::Election {
+                term: reader.u64()?,
+                candidate: reader.node_id()?,
+                table_version: reader.u32()?,
+                pre_vote: reader.u8()? != 0,
+            },
+            SselVotePacket => Message::Vote {
+                term: reader.u64()?,
+                granted: reader.u8()? != 0,
+                pre_vote: reader.u8()? != 0,
+            },
+            SselFinPacket => Message::Heartbeat {
+                term: reader.u64()?,
+                leader: reader.node_id()?,
+                table_version: reader.u32()?,
+            },
+            SsslPacket => Message::Sleep {
+                id: reader.u32()?,
+                action: SleepAction::try_from(reader.u8()?)
+                    .map_err(|_| PacketError::InvalidPayload("unknown sleep action"))?,
+            },
+            SsslAckPacket => Message::SleepAck {
+                id: reader.u32()?,
+                accepted: reader.u8()? != 0,
+            },
+        };
+        if !reader.is_empty() {
+            return Err(PacketError::InvalidPayload("trailing bytes"));
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::{HEADER_SIZE, PROTOCOL_VERSION};
+    use crate::pcinfo::{PCStatus, WakeRequest};
+
+    const CLUSTER_ID: u16 = 7;
+
+    fn pc_info() -> PCInfo {
+        let mut pc_info = PCInfo::new(
+            NodeId::random(),
+            "lab-1".to_string(),
+            MacAddress::new([0x02, 0, 0, 0, 0, 0x01]),
+            "fd00::1".parse().unwrap(),
+            PCStatus::Offline,
+            false,
+        );
+        pc_info.join_group("build-farm");
+        pc_info
+    }
+
+    fn schedule() -> Schedule {
+        Schedule {
+            name: "mornings".to_string(),
+            cron: "30 7 * * 1-5".parse().unwrap(),
+            request: WakeRequest::Group {
+                name: "build-farm".to_string(),
+                except: vec!["lab-2".to_string()],
+            },
+            last_run: 29_000_000,
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        let id = NodeId::random();
+        let mac = MacAddress::new([0x02, 0, 0, 0, 0, 0x02]);
+        vec![
+            Message::Status,
+            Message::StatusAck,
+            Message::Exit { id },
+            Message::Discovery {
+                id,
+                hostname: "lab-2".to_string(),
+                mac,
+            },
+            Message::DiscoveryAck {
+                id,
+                hostname: "lab-2".to_string(),
+                mac,
+            },
+            Message::Replication {
+                table_version: 3,
+                index: 1,
+                count: 2,
+                checksum: 0xDEAD_BEEF,
+                data: vec![1, 2, 3],
+            },
+            Message::ReplicationDelta {
+                table_version: 4,
+                update_type: UpdateType::Change,
+                pc_info: pc_info(),
+            },
+            Message::ScheduleDelta {
+                table_version: 5,
+                update_type: UpdateType::Add,
+                schedule: schedule(),
+            },
+            Message::SnapshotRequest { table_version: 6 },
+            Message::ReplicationAck { table_version: 7 },
+            Message::Election {
+                term: 8,
+                candidate: id,
+                table_version: 9,
+                pre_vote: true,
+            },
+            Message::Vote {
+                term: 8,
+                granted: true,
+                pre_vote: false,
+            },
+            Message::Heartbeat {
+                term: 8,
+                leader: id,
+                table_version: 9,
+            },
+            Message::Sleep {
+                id: 10,
+                action: SleepAction::PowerOff,
+            },
+            Message::SleepAck {
+                id: 10,
+                accepted: false,
+            },
+        ]
+    }
+
+    // A packet whose header announces the payload as it is
+    fn packet(packet_type: PacketType, payload: &[u8]) -> Vec<u8> {
+        let header = make_header(packet_type, payload.len(), CLUSTER_ID);
+        [&header[..], payload].concat()
+    }
+
+    #[test]
+    fn round_trip() {
+        for message in messages() {
+            let decoded = Message::decode(&message.encode(CLUSTER_ID), CLUSTER_ID);
+            assert_eq!(decoded, Ok(message));
+        }
+    }
+
+    #[test]
+    fn truncated_packet() {
+        for message in messages() {
+            let encoded = message.encode(CLUSTER_ID);
+            for len in 0..encoded.len() {
+                assert_eq!(
+                    Message::decode(&encoded[..len], CLUSTER_ID),
+                    Err(PacketError::Truncated),
+                    "{:?} cut to {} bytes",
+                    message,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        for message in messages() {
+            let encoded = message.encode(CLUSTER_ID);
+            let payload = &encoded[HEADER_SIZE..];
+            for len in 0..payload.len() {
+                let short = packet(message.packet_type(), &payload[..len]);
+                assert!(
+                    Message::decode(&short, CLUSTER_ID).is_err(),
+                    "{:?} cut to {} payload bytes",
+                    message,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_payload() {
+        for message in messages() {
+            let encoded = message.encode(CLUSTER_ID);
+            let mut payload = encoded[HEADER_SIZE..].to_vec();
+            payload.push(0);
+            assert_eq!(
+                Message::decode(&packet(message.packet_type(), &payload), CLUSTER_ID),
+                Err(PacketError::InvalidPayload("trailing bytes")),
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn bytes_past_the_announced_length_are_ignored() {
+        // Such as the authentication trailer, which Authenticator strips
+        let mut encoded = Message::Status.encode(CLUSTER_ID);
+        encoded.extend([0; 4]);
+        assert_eq!(Message::decode(&encoded, CLUSTER_ID), Ok(Message::Status));
+    }
+
+    #[test]
+    fn other_clusters_and_versions() {
+        let encoded = Message::Status.encode(CLUSTER_ID);
+        assert_eq!(
+            Message::decode(&encoded, CLUSTER_ID + 1),
+            Err(PacketError::WrongCluster(CLUSTER_ID))
+        );
+
+        // The version is the 9th header byte
+        let mut old = encoded.clone();
+        old[8] = PROTOCOL_VERSION - 1;
+        assert_eq!(
+            Message::decode(&old, CLUSTER_ID),
+            Err(PacketError::UnsupportedVersion(PROTOCOL_VERSION - 1))
+        );
+
+        let mut garbage = encoded;
+        garbage[0] = 0;
+        assert_eq!(
+            Message::decode(&garbage, CLUSTER_ID),
+            Err(PacketError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn unknown_values() {
+        // The type is the 4th header byte, 0x03 was never used
+        let mut encoded = Message::Status.encode(CLUSTER_ID);
+        encoded[3] = 0x03;
+        assert_eq!(
+            Message::decode(&encoded, CLUSTER_ID),
+            Err(PacketError::UnknownType(0x03))
+        );
+
+        let sleep = packet(PacketType::SsslPacket, &[0, 0, 0, 1, 0x09]);
+        assert_eq!(
+            Message::decode(&sleep, CLUSTER_ID),
+            Err(PacketError::InvalidPayload("unknown sleep action"))
+        );
+
+        let ip = [9, 1, 2, 3, 4];
+        assert_eq!(
+            Reader::new(&ip).ip(),
+            Err(PacketError::InvalidPayload("unknown address family"))
+        );
+    }
+
+    #[test]
+    fn strings_must_be_utf8() {
+        let mut payload = NodeId::random().bytes().to_vec();
+        payload.extend([0x02, 0, 0, 0, 0, 0x02]);
+        write_bytes(&mut payload, &[0xFF, 0xFE]);
+        assert_eq!(
+            Message::decode(&packet(PacketType::SsdPacket, &payload), CLUSTER_ID),
+            Err(PacketError::InvalidPayload("string is not utf-8"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "field too long for a packet")]
+    fn write_bytes_refuses_to_truncate() {
+        write_bytes(&mut Vec::new(), &vec![0; u16::MAX as usize + 1]);
+    }
+}
@@ -0,0 +1,194 @@
+mod auth;
+mod fragment;
+mod message;
+
+pub use auth::{Authenticator, Socket};
+pub use fragment::{decode_snapshot, encode_snapshot, fragment, Reassembler, Snapshot};
+pub use message::{write_bytes, write_ip, write_string, Message, Reader, SleepAction};
+
+use mac_address::MacAddress;
+
+pub const BUFFER_SIZE: usize = 1024;
+pub const HEADER_SIZE: usize = 10;
+pub const PROTOCOL_VERSION: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(clippy::enum_variant_names)]
+pub enum PacketType {
+    SsrPacket = 0x01,
+    SsrAckPacket = 0x02,
+    SsePacket = 0x04,
+    SsdPacket = 0x05,
+    SsdAckPacket = 0x06,
+    SsrepPacket = 0x07,
+    SselPacket = 0x08,
+    SselFinPacket = 0x09,
+    SsrepDeltaPacket = 0x0B,
+    SsrepReqPacket = 0x0C,
+    SsrepAckPacket = 0x0D,
+    SselVotePacket = 0x0E,
+    SsrepSchedPacket = 0x0F,
+    SsslPacket = 0x10,
+    SsslAckPacket = 0x11,
+}
+
+impl std::convert::TryFrom<u8> for PacketType {
+    type Error = PacketError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(PacketType::SsrPacket),
+            0x02 => Ok(PacketType::SsrAckPacket),
+            0x04 => Ok(PacketType::SsePacket),
+            0x05 => Ok(PacketType::SsdPacket),
+            0x06 => Ok(PacketType::SsdAckPacket),
+            0x07 => Ok(PacketType::SsrepPacket),
+            0x08 => Ok(PacketType::SselPacket),
+            0x09 => Ok(PacketType::SselFinPacket),
+            0x0B => Ok(PacketType::SsrepDeltaPacket),
+            0x0C => Ok(PacketType::SsrepReqPacket),
+            0x0D => Ok(PacketType::SsrepAckPacket),
+            0x0E => Ok(PacketType::SselVotePacket),
+            0x0F => Ok(PacketType::SsrepSchedPacket),
+            0x10 => Ok(PacketType::SsslPacket),
+            0x11 => Ok(PacketType::SsslAckPacket),
+            _ => Err(PacketError::UnknownType(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    WrongCluster(u16),
+    Unauthenticated,
+    Replayed,
+    InvalidPayload(&'static str),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "packet is truncated"),
+            PacketError::BadMagic => write!(f, "bad magic number"),
+            PacketError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            PacketError::UnknownType(t) => write!(f, "unknown packet type {:#04x}", t),
+            PacketError::WrongCluster(c) => write!(f, "packet belongs to cluster {}", c),
+            PacketError::Unauthenticated => write!(f, "packet failed authentication"),
+            PacketError::Replayed => write!(f, "packet was replayed"),
+            PacketError::InvalidPayload(what) => write!(f, "invalid payload: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+// Header layout:
+// | magic (2) | flags (1) | type (1) | cluster id (2) | length (2) | version (1) | reserved (1) |
+const MAGIC_NUMBER: u16 = 0xCA31;
+const MAGIC_NUMBER_INDEX: usize = 0;
+const FLAGS_INDEX: usize = 2;
+const PACKET_TYPE_INDEX: usize = 3;
+const CLUSTER_ID_INDEX: usize = 4;
+const LENGTH_INDEX: usize = 6;
+const VERSION_INDEX: usize = 8;
+
+pub fn make_header(packet_type: PacketType, length: usize, cluster_id: u16) -> [u8; HEADER_SIZE] {
+    let length = length as u16;
+    [
+        (MAGIC_NUMBER >> 8) as u8,
+        MAGIC_NUMBER as u8,
+        0,
+        packet_type as u8,
+        (cluster_id >> 8) as u8,
+        cluster_id as u8,
+        (length >> 8) as u8,
+        length as u8,
+        PROTOCOL_VERSION,
+        0,
+    ]
+}
+
+// Checks the header and returns the packet type and its payload
+pub fn parse_header(packet: &[u8], cluster_id: u16) -> Result<(PacketType, &[u8]), PacketError> {
+    if packet.len() < HEADER_SIZE {
+        return Err(PacketError::Truncated);
+    }
+    let magic_number =
+        (packet[MAGIC_NUMBER_INDEX] as u16) << 8 | packet[MAGIC_NUMBER_INDEX + 1] as u16;
+    if magic_number != MAGIC_NUMBER {
+        return Err(PacketError::BadMagic);
+    }
+    if packet[VERSION_INDEX] != PROTOCOL_VERSION {
+        return Err(PacketError::UnsupportedVersion(packet[VERSION_INDEX]));
+    }
+    let packet_cluster_id =
+        (packet[CLUSTER_ID_INDEX] as u16) << 8 | packet[CLUSTER_ID_INDEX + 1] as u16;
+    if packet_cluster_id != cluster_id {
+        // From another cluster sharing the network
+        return Err(PacketError::WrongCluster(packet_cluster_id));
+    }
+    let packet_type = PacketType::try_from(packet[PACKET_TYPE_INDEX])?;
+    let length = (packet[LENGTH_INDEX] as usize) << 8 | packet[LENGTH_INDEX + 1] as usize;
+    let payload = packet
+        .get(HEADER_SIZE..HEADER_SIZE + length)
+        .ok_or(PacketError::Truncated)?;
+    Ok((packet_type, payload))
+}
+
+// Password some NICs (SecureOn) expect right after the magic packet,
+// written like a MAC address when 6 bytes long and like an IPv4 address
+// when 4 bytes long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecureOn(Vec<u8>);
+
+impl SecureOn {
+    pub fn new(bytes: Vec<u8>) -> Result<SecureOn, PacketError> {
+        match bytes.len() {
+            4 | 6 => Ok(SecureOn(bytes)),
+            _ => Err(PacketError::InvalidPayload("SecureOn password length")),
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for SecureOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid SecureOn password '{}'", s);
+        let bytes = if s.contains('.') {
+            s.parse::<std::net::Ipv4Addr>()
+                .map_err(|_| invalid())?
+                .octets()
+                .to_vec()
+        } else {
+            s.split([':', '-'])
+                .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| invalid()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        SecureOn::new(bytes).map_err(|_| invalid())
+    }
+}
+
+pub fn make_wakeup_packet(mac: &MacAddress, secure_on: Option<&SecureOn>) -> Vec<u8> {
+    const FF_NUM: usize = 6;
+    const WOL_HEADER: [u8; FF_NUM] = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+    const MAC_NUM: usize = 16;
+    const MAC_SIZE: usize = 6;
+    let wol_payload: Vec<u8> = mac
+        .bytes()
+        .iter()
+        .cycle()
+        .take(MAC_NUM * MAC_SIZE)
+        .cloned()
+        .collect();
+    let password = secure_on.map(SecureOn::bytes).unwrap_or_default();
+    [&WOL_HEADER[..], &wol_payload, password].concat()
+}
@@ -2,15 +2,19 @@
 Create a struct called PCInfo with the following fields:
 - name: String
 - mac: MacAddress
-- ip: Ipv4Addr
+- ip: IpAddr
 - status: enum Status
 - is_manager: bool
 */
 
+use std::collections::BTreeSet;
 use std::net::{IpAddr, Ipv4Addr};
 extern crate mac_address;
 use mac_address::MacAddress;
 
+use crate::nodeid::NodeId;
+use crate::packets::{write_bytes, write_ip, write_string, PacketError, Reader, SecureOn};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PCStatus {
@@ -29,17 +33,203 @@ impl std::convert::TryFrom<u8> for PCStatus {
     }
 }
 
+// How the magic packet reaches a PC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeVia {
+    // Broadcast on our own segment
+    Local,
+    // The broadcast address of the PC's subnet, for routers that
+    // forward directed broadcasts
+    Directed(Ipv4Addr),
+    // A host on the PC's segment that passes the packet on
+    Relay(IpAddr),
+    // A raw Ethernet frame on our own segment, for NICs and switches that
+    // ignore magic packets over UDP
+    Ethernet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeTarget {
+    pub via: WakeVia,
+    // Usually 7 or 9, the configured wakeup port when unset
+    pub port: Option<u16>,
+}
+
+impl Default for WakeTarget {
+    fn default() -> Self {
+        Self {
+            via: WakeVia::Local,
+            port: None,
+        }
+    }
+}
+
+impl WakeTarget {
+    fn read(reader: &mut Reader) -> Result<WakeTarget, PacketError> {
+        let via = match reader.u8()? {
+            0x00 => WakeVia::Local,
+            0x01 => match reader.ip()? {
+                IpAddr::V4(ip) => WakeVia::Directed(ip),
+                IpAddr::V6(_) => {
+                    return Err(PacketError::InvalidPayload("directed broadcast over IPv6"))
+                }
+            },
+            0x02 => WakeVia::Relay(reader.ip()?),
+            0x03 => WakeVia::Ethernet,
+            _ => return Err(PacketError::InvalidPayload("unknown wake target")),
+        };
+        let port = match reader.u16()? {
+            0 => None,
+            port => Some(port),
+        };
+        Ok(WakeTarget { via, port })
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        match self.via {
+            WakeVia::Local => bytes.push(0x00),
+            WakeVia::Directed(ip) => {
+                bytes.push(0x01);
+                write_ip(bytes, &IpAddr::V4(ip));
+            }
+            WakeVia::Relay(ip) => {
+                bytes.push(0x02);
+                write_ip(bytes, &ip);
+            }
+            WakeVia::Ethernet => bytes.push(0x03),
+        }
+        bytes.extend(self.port.unwrap_or(0).to_be_bytes());
+    }
+}
+
+impl std::fmt::Display for WakeTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.via {
+            WakeVia::Local => write!(f, "local broadcast")?,
+            WakeVia::Directed(ip) => write!(f, "directed broadcast to {}", ip)?,
+            WakeVia::Relay(ip) => write!(f, "relay {}", ip)?,
+            WakeVia::Ethernet => write!(f, "raw Ethernet")?,
+        }
+        match self.port {
+            Some(port) => write!(f, " port {}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+// Keeps wake groups small enough for a PC to fit in one packet
+pub const MAX_GROUPS: usize = 8;
+pub const MAX_NAME: usize = 32;
+
+// Group and schedule names
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// A change to how a PC is woken up, made from the command interface
+#[derive(Debug, Clone)]
+pub enum WakeSetting {
+    Target(WakeTarget),
+    SecureOn(Option<SecureOn>),
+    JoinGroup(String),
+    LeaveGroup(String),
+}
+
+// What to wake up, from the wakeup command or a schedule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WakeRequest {
+    Host(String),
+    Group { name: String, except: Vec<String> },
+    All { except: Vec<String> },
+}
+
+impl WakeRequest {
+    pub fn read(reader: &mut Reader) -> Result<WakeRequest, PacketError> {
+        let kind = reader.u8()?;
+        if kind == 0x00 {
+            return Ok(WakeRequest::Host(reader.string()?));
+        }
+        let name = if kind == 0x01 {
+            Some(reader.string()?)
+        } else {
+            None
+        };
+        let except = (0..reader.u8()?)
+            .map(|_| reader.string())
+            .collect::<Result<Vec<_>, _>>()?;
+        match (kind, name) {
+            (0x01, Some(name)) => Ok(WakeRequest::Group { name, except }),
+            (0x02, None) => Ok(WakeRequest::All { except }),
+            _ => Err(PacketError::InvalidPayload("unknown wake request")),
+        }
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        let except = match self {
+            WakeRequest::Host(hostname) => {
+                bytes.push(0x00);
+                write_string(bytes, hostname);
+                return;
+            }
+            WakeRequest::Group { name, except } => {
+                bytes.push(0x01);
+                write_string(bytes, name);
+                except
+            }
+            WakeRequest::All { except } => {
+                bytes.push(0x02);
+                except
+            }
+        };
+        // Node::edit_schedule keeps it within bounds
+        bytes.push(u8::try_from(except.len()).expect("too many exceptions"));
+        for hostname in except {
+            write_string(bytes, hostname);
+        }
+    }
+}
+
+impl std::fmt::Display for WakeRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let except = match self {
+            WakeRequest::Host(hostname) => return write!(f, "{}", hostname),
+            WakeRequest::Group { name, except } => {
+                write!(f, "group {}", name)?;
+                except
+            }
+            WakeRequest::All { except } => {
+                write!(f, "all")?;
+                except
+            }
+        };
+        if !except.is_empty() {
+            write!(f, " --except {}", except.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PCInfo {
+    id: NodeId,
+    // Only shown to the user, several PCs may share one
     name: String,
     mac: MacAddress,
     ip: IpAddr,
     status: PCStatus,
     is_manager: bool,
+    wake_target: WakeTarget,
+    secure_on: Option<SecureOn>,
+    groups: BTreeSet<String>,
 }
 
 impl PCInfo {
     pub fn new(
+        id: NodeId,
         name: String,
         mac: MacAddress,
         ip: IpAddr,
@@ -47,75 +237,91 @@ impl PCInfo {
         is_manager: bool,
     ) -> PCInfo {
         PCInfo {
+            id,
             name,
             mac,
             ip,
             status,
             is_manager,
+            wake_target: WakeTarget::default(),
+            secure_on: None,
+            groups: BTreeSet::new(),
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<(PCInfo, usize), ()> {
-        const HSTNM_LEN_BYTES: usize = std::mem::size_of::<usize>();
-        let hostname_len_bytes = match bytes[..HSTNM_LEN_BYTES].try_into() {
-            Ok(bytes) => bytes,
-            Err(_) => {
-                return Err(())},
+    pub fn read(reader: &mut Reader) -> Result<PCInfo, PacketError> {
+        let id = reader.node_id()?;
+        let name = reader.string()?;
+        let mac = reader.mac()?;
+        let ip = reader.ip()?;
+        let status = PCStatus::try_from(reader.u8()?)
+            .map_err(|_| PacketError::InvalidPayload("unknown status"))?;
+        let is_manager = reader.u8()? == 0x01;
+        let wake_target = WakeTarget::read(reader)?;
+        // Empty when the PC has no SecureOn password
+        let secure_on = match reader.bytes()? {
+            password if password.is_empty() => None,
+            password => Some(SecureOn::new(password)?),
         };
-        let hostname_len = usize::from_be_bytes(
-            hostname_len_bytes,
-        ) as usize;
-        let mut bytes_used: usize = HSTNM_LEN_BYTES;
-
-        let hostname = String::from_utf8(bytes[bytes_used..bytes_used + hostname_len].to_vec()).unwrap();
-        bytes_used += hostname_len;
-    
-        let mac = MacAddress::new(
-            bytes[bytes_used..bytes_used + 6]
-                .try_into()
-                .map_err(|_| ())?,
-        );
-        bytes_used += 6;
-
-        let ip = IpAddr::V4(Ipv4Addr::new(
-            bytes[bytes_used],
-            bytes[bytes_used + 1],
-            bytes[bytes_used + 2],
-            bytes[bytes_used + 3],
-        ));
-        bytes_used += 4;
-
-        let status = PCStatus::try_from(bytes[bytes_used]).map_err(|_| ())?;
-        bytes_used += 1;
-
-        let is_manager = bytes[bytes_used] == 0x01;
-        bytes_used += 1;
-        
-        Ok((PCInfo {
-            name: hostname,
+        let group_count = reader.u8()? as usize;
+        if group_count > MAX_GROUPS {
+            return Err(PacketError::InvalidPayload("too many groups"));
+        }
+        let mut groups = BTreeSet::new();
+        for _ in 0..group_count {
+            let group = reader.string()?;
+            if !valid_name(&group) {
+                return Err(PacketError::InvalidPayload("invalid group name"));
+            }
+            groups.insert(group);
+        }
+
+        Ok(PCInfo {
+            id,
+            name,
             mac,
             ip,
             status,
             is_manager,
-        }, bytes_used))
-
+            wake_target,
+            secure_on,
+            groups,
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        bytes.extend(self.get_hostname().len().to_be_bytes().iter());
-        bytes.extend(self.get_hostname().as_bytes());
+        bytes.extend(self.id.bytes());
+        write_string(&mut bytes, self.get_hostname());
         bytes.extend(self.get_mac().bytes().iter());
-        let ip_octets = match self.get_ip() {
-            IpAddr::V4(ip) => ip.octets(),
-            _ => [0, 0, 0, 0],
-        };
-        bytes.extend(ip_octets.iter());
+        write_ip(&mut bytes, self.get_ip());
         bytes.push(self.status.clone() as u8);
         bytes.push(if self.is_manager { 0x01 } else { 0x00 });
+        self.wake_target.write(&mut bytes);
+        write_bytes(
+            &mut bytes,
+            self.secure_on
+                .as_ref()
+                .map(SecureOn::bytes)
+                .unwrap_or_default(),
+        );
+        bytes.push(u8::try_from(self.groups.len()).expect("too many groups"));
+        for group in &self.groups {
+            write_string(&mut bytes, group);
+        }
         bytes
     }
 
+    pub fn get_id(&self) -> &NodeId {
+        &self.id
+    }
+
+    // Whether the user means this PC by its hostname, its id or the short
+    // form of it
+    pub fn is_called(&self, name: &str) -> bool {
+        self.name == name || self.id.to_string() == name || self.id.short() == name
+    }
+
     pub fn get_name(&self) -> &String {
         &self.name
     }
@@ -148,11 +354,54 @@ impl PCInfo {
         self.is_manager
     }
 
+    pub fn get_wake_target(&self) -> &WakeTarget {
+        &self.wake_target
+    }
+
+    pub fn set_wake_target(&mut self, wake_target: WakeTarget) {
+        self.wake_target = wake_target;
+    }
+
+    pub fn get_secure_on(&self) -> Option<&SecureOn> {
+        self.secure_on.as_ref()
+    }
+
+    pub fn set_secure_on(&mut self, secure_on: Option<SecureOn>) {
+        self.secure_on = secure_on;
+    }
+
+    pub fn get_groups(&self) -> &BTreeSet<String> {
+        &self.groups
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.contains(group)
+    }
+
+    // False when already a member or in too many groups
+    pub fn join_group(&mut self, group: &str) -> bool {
+        if self.groups.len() >= MAX_GROUPS {
+            return false;
+        }
+        self.groups.insert(group.to_string())
+    }
+
+    pub fn leave_group(&mut self, group: &str) -> bool {
+        self.groups.remove(group)
+    }
+
+    // Keeps what the manager was told about waking a PC up, which
+    // discovery knows nothing about
+    pub fn keep_wake_settings(&mut self, known: &PCInfo) {
+        self.wake_target = known.wake_target;
+        self.secure_on = known.secure_on.clone();
+        self.groups = known.groups.clone();
+    }
+
     pub fn set_status(&mut self, status: PCStatus) {
         self.status = status;
     }
 
-    #[allow(dead_code)]
     pub fn is_online(&self) -> bool {
         self.status == PCStatus::Online
     }
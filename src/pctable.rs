@@ -0,0 +1,254 @@
+use crate::events::Event;
+use crate::nodeid::NodeId;
+use crate::pcinfo::{PCInfo, PCStatus};
+use crate::signals::Signals;
+use mac_address::MacAddress;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default)]
+struct Pcs {
+    by_id: HashMap<NodeId, PCInfo>,
+    by_mac: HashMap<MacAddress, NodeId>,
+    by_ip: HashMap<IpAddr, NodeId>,
+}
+
+impl Pcs {
+    fn unindex(&mut self, pc_info: &PCInfo) {
+        let id = pc_info.get_id();
+        // Another PC may have taken over the address since
+        if self.by_mac.get(pc_info.get_mac()) == Some(id) {
+            self.by_mac.remove(pc_info.get_mac());
+        }
+        if self.by_ip.get(pc_info.get_ip()) == Some(id) {
+            self.by_ip.remove(pc_info.get_ip());
+        }
+    }
+
+    fn insert(&mut self, pc_info: PCInfo) {
+        let id = *pc_info.get_id();
+        if let Some(old) = self.by_id.remove(&id) {
+            self.unindex(&old);
+        }
+        self.by_mac.insert(*pc_info.get_mac(), id);
+        self.by_ip.insert(*pc_info.get_ip(), id);
+        self.by_id.insert(id, pc_info);
+    }
+
+    fn remove(&mut self, id: &NodeId) -> Option<PCInfo> {
+        let pc_info = self.by_id.remove(id)?;
+        self.unindex(&pc_info);
+        Some(pc_info)
+    }
+
+    fn by_mac(&self, mac: &MacAddress) -> Option<&PCInfo> {
+        self.by_id.get(self.by_mac.get(mac)?)
+    }
+
+    fn by_ip(&self, ip: &IpAddr) -> Option<&PCInfo> {
+        self.by_id.get(self.by_ip.get(ip)?)
+    }
+}
+
+// The table as it was at one version, unaffected by later changes
+#[derive(Debug, Clone)]
+pub struct PcSnapshot {
+    version: u64,
+    pcs: Arc<Pcs>,
+}
+
+impl PcSnapshot {
+    // Counts the changes to this node's table, unlike the replicated
+    // table version
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&PCInfo> {
+        self.pcs.by_id.get(id)
+    }
+
+    // The PCs going by a name, see PCInfo::is_called
+    pub fn find(&self, name: &str) -> Vec<&PCInfo> {
+        self.iter()
+            .filter(|pc_info| pc_info.is_called(name))
+            .collect()
+    }
+
+    pub fn by_mac(&self, mac: &MacAddress) -> Option<&PCInfo> {
+        self.pcs.by_mac(mac)
+    }
+
+    pub fn by_ip(&self, ip: &IpAddr) -> Option<&PCInfo> {
+        self.pcs.by_ip(ip)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PCInfo> {
+        self.pcs.by_id.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pcs.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pcs.by_id.is_empty()
+    }
+}
+
+// The PCs a node knows about: every other PC on the manager, only the
+// manager on participants. Readers take a snapshot, which is cheap and
+// holds no lock, and each change is made at once and published as events.
+#[derive(Debug)]
+pub struct PcTable {
+    signals: Arc<Signals>,
+    // Cloned on write while snapshots of it are around
+    current: Mutex<(u64, Arc<Pcs>)>,
+}
+
+impl PcTable {
+    pub(crate) fn new(signals: Arc<Signals>) -> Self {
+        Self {
+            signals,
+            current: Mutex::new((0, Arc::new(Pcs::default()))),
+        }
+    }
+
+    pub fn snapshot(&self) -> PcSnapshot {
+        let current = self.current.lock().unwrap();
+        PcSnapshot {
+            version: current.0,
+            pcs: current.1.clone(),
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<PCInfo> {
+        self.snapshot().get(id).cloned()
+    }
+
+    pub fn find(&self, name: &str) -> Vec<PCInfo> {
+        self.snapshot().find(name).into_iter().cloned().collect()
+    }
+
+    pub fn by_mac(&self, mac: &MacAddress) -> Option<PCInfo> {
+        self.snapshot().by_mac(mac).cloned()
+    }
+
+    pub fn by_ip(&self, ip: &IpAddr) -> Option<PCInfo> {
+        self.snapshot().by_ip(ip).cloned()
+    }
+
+    // Runs a change under the lock, publishing its events and counting a
+    // new version if anything changed
+    fn update<R>(&self, update: impl FnOnce(&mut Pcs, &mut Vec<Event>) -> R) -> R {
+        let mut current = self.current.lock().unwrap();
+        let mut events = Vec::new();
+        let result = update(Arc::make_mut(&mut current.1), &mut events);
+        if !events.is_empty() {
+            current.0 += 1;
+        }
+        // Still under the lock, so that subscribers see changes in order
+        for event in events {
+            self.signals.publish(event);
+        }
+        result
+    }
+
+    // Adds a PC or refreshes what we know of it, keeping the wake settings
+    // made on the manager. Returns the PC as stored if anything changed.
+    pub(crate) fn add(&self, mut pc_info: PCInfo) -> Option<PCInfo> {
+        self.update(|pcs, events| {
+            if let Some(known) = pcs.by_id.get(pc_info.get_id()) {
+                pc_info.keep_wake_settings(known);
+                if *known == pc_info {
+                    return None;
+                }
+            }
+            if pc_info.is_manager() {
+                // A newly elected manager replaces the previous one
+                let managers = pcs
+                    .by_id
+                    .values()
+                    .filter(|known| known.is_manager())
+                    .map(|known| *known.get_id())
+                    .filter(|id| id != pc_info.get_id())
+                    .collect::<Vec<_>>();
+                for id in managers {
+                    events.extend(pcs.remove(&id).map(Event::PcRemoved));
+                }
+            }
+            pcs.insert(pc_info.clone());
+            events.push(Event::PcAdded(pc_info.clone()));
+            Some(pc_info)
+        })
+    }
+
+    pub(crate) fn set_status(&self, id: &NodeId, status: PCStatus) -> Option<PCInfo> {
+        self.update(|pcs, events| {
+            let pc_info = pcs.by_id.get_mut(id)?;
+            pc_info.set_status(status.clone());
+            events.push(Event::StatusChanged {
+                id: *id,
+                hostname: pc_info.get_hostname().clone(),
+                status,
+            });
+            Some(pc_info.clone())
+        })
+    }
+
+    // Applies a change to a PC's settings, kept only when it returns true.
+    // Returns the changed PC.
+    pub(crate) fn change(
+        &self,
+        id: &NodeId,
+        change: impl FnOnce(&mut PCInfo) -> bool,
+    ) -> Option<PCInfo> {
+        self.update(|pcs, events| {
+            let mut pc_info = pcs.by_id.get(id)?.clone();
+            if !change(&mut pc_info) {
+                return None;
+            }
+            pcs.insert(pc_info.clone());
+            events.push(Event::PcChanged(pc_info.clone()));
+            Some(pc_info)
+        })
+    }
+
+    pub(crate) fn remove(&self, id: &NodeId) -> Option<PCInfo> {
+        self.update(|pcs, events| {
+            let pc_info = pcs.remove(id)?;
+            events.push(Event::PcRemoved(pc_info.clone()));
+            Some(pc_info)
+        })
+    }
+
+    pub(crate) fn retain(&self, keep: impl Fn(&PCInfo) -> bool) {
+        self.update(|pcs, events| {
+            let dropped = pcs
+                .by_id
+                .values()
+                .filter(|pc_info| !keep(pc_info))
+                .map(|pc_info| *pc_info.get_id())
+                .collect::<Vec<_>>();
+            for id in dropped {
+                events.extend(pcs.remove(&id).map(Event::PcRemoved));
+            }
+        })
+    }
+
+    // Swaps the whole table, e.g. for the replicated one when we become
+    // the manager
+    pub(crate) fn replace(&self, pc_infos: impl IntoIterator<Item = PCInfo>) {
+        self.update(|pcs, events| {
+            for pc_info in std::mem::take(&mut pcs.by_id).into_values() {
+                events.push(Event::PcRemoved(pc_info));
+            }
+            *pcs = Pcs::default();
+            for pc_info in pc_infos {
+                events.push(Event::PcAdded(pc_info.clone()));
+                pcs.insert(pc_info);
+            }
+        })
+    }
+}
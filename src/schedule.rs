@@ -0,0 +1,418 @@
+use crate::packets::{write_string, PacketError, Reader};
+use crate::pcinfo::{valid_name, WakeRequest};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Keeps a schedule small enough to fit in one packet
+pub const MAX_SCHEDULE_SIZE: usize = 512;
+
+// The five fields of a crontab line, matched against local time (UTC
+// off Unix):
+// minute hour day-of-month month day-of-week
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day of month and the day of week fields were left as
+    // *, when both are restricted either one matching is enough
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// Parses a field like "*", "*/15", "1-5", "0,30" or "8-18/2" into
+// a bitmask of the values it covers
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field '{}'", field);
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (
+                    first.parse().map_err(|_| invalid())?,
+                    last.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    // A step after a single value runs to the end, as in cron
+                    let last = if part.contains('/') { max } else { value };
+                    (value, last)
+                }
+            },
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl std::str::FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("Expected 5 cron fields, got {}", fields.len()));
+        };
+        let mut weekday_mask = parse_field(weekdays, 0, 7)?;
+        // Sunday is both 0 and 7
+        if weekday_mask & (1 << 7) != 0 {
+            weekday_mask = (weekday_mask | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            source: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_mask,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn has(mask: u64, value: i32) -> bool {
+    mask & (1 << value) != 0
+}
+
+// The parts of a minute crons look at, month and day counting from 1
+// and weekday from Sunday as 0
+struct Time {
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    weekday: i32,
+}
+
+#[cfg(unix)]
+fn local_time(minute: u64) -> Option<Time> {
+    let time = (minute * 60) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(Time {
+        year: tm.tm_year + 1900,
+        month: tm.tm_mon + 1,
+        day: tm.tm_mday,
+        hour: tm.tm_hour,
+        minute: tm.tm_min,
+        weekday: tm.tm_wday,
+    })
+}
+
+// Without localtime_r there is no time zone to go by, so crons follow UTC
+#[cfg(not(unix))]
+fn local_time(minute: u64) -> Option<Time> {
+    let days = i64::try_from(minute / (24 * 60)).ok()?;
+    let minute_of_day = (minute % (24 * 60)) as i32;
+    // Howard Hinnant's days_from_civil, backwards
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    Some(Time {
+        year: i32::try_from(year).ok()?,
+        month: month as i32,
+        day: day as i32,
+        hour: minute_of_day / 60,
+        minute: minute_of_day % 60,
+        // The epoch was a Thursday
+        weekday: ((days + 4).rem_euclid(7)) as i32,
+    })
+}
+
+pub fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+// As YYYY-MM-DD HH:MM in local time
+pub fn format_minute(minute: u64) -> String {
+    match local_time(minute) {
+        Some(time) => format!(
+            "{}-{:02}-{:02} {:02}:{:02}",
+            time.year, time.month, time.day, time.hour, time.minute
+        ),
+        None => "-".to_string(),
+    }
+}
+
+impl Cron {
+    // Whether the cron fires during the given minute since the epoch
+    pub fn matches(&self, minute: u64) -> bool {
+        local_time(minute)
+            .is_some_and(|time| self.matches_hour(&time) && has(self.minutes, time.minute))
+    }
+
+    fn matches_hour(&self, time: &Time) -> bool {
+        let day = has(self.days, time.day);
+        let weekday = has(self.weekdays, time.weekday);
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.hours, time.hour) && has(self.months, time.month) && day_matches
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub name: String,
+    pub cron: Cron,
+    pub request: WakeRequest,
+    // Minute since the epoch the schedule last fired in, replicated so
+    // that a new manager neither repeats nor misses a run
+    pub last_run: u64,
+}
+
+// A change to the schedules asked of the manager
+#[derive(Debug, Clone)]
+pub enum ScheduleEdit {
+    Add(Schedule),
+    Remove(String),
+}
+
+impl Schedule {
+    pub fn read(reader: &mut Reader) -> Result<Schedule, PacketError> {
+        let name = reader.string()?;
+        if !valid_name(&name) {
+            return Err(PacketError::InvalidPayload("invalid schedule name"));
+        }
+        let cron = reader
+            .string()?
+            .parse()
+            .map_err(|_| PacketError::InvalidPayload("invalid cron expression"))?;
+        let request = WakeRequest::read(reader)?;
+        let last_run = reader.u64()?;
+        Ok(Schedule {
+            name,
+            cron,
+            request,
+            last_run,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, &self.name);
+        write_string(&mut bytes, &self.cron.source);
+        self.request.write(&mut bytes);
+        bytes.extend(self.last_run.to_be_bytes());
+        bytes
+    }
+
+    // The first minute after the last run the schedule fires in, looking
+    // no further than a year ahead
+    pub fn next_run(&self, now: u64) -> Option<u64> {
+        const YEAR: u64 = 366 * 24 * 60;
+        let mut minute = self.last_run.max(now) + 1;
+        let end = minute + YEAR;
+        while minute < end {
+            let time = local_time(minute)?;
+            if !self.cron.matches_hour(&time) {
+                // Nothing left to match in this hour
+                minute += 60 - time.minute as u64;
+            } else if has(self.cron.minutes, time.minute) {
+                return Some(minute);
+            } else {
+                minute += 1;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(source: &str) -> Cron {
+        source.parse().unwrap()
+    }
+
+    // Noon on a day of 2026, March 13th being a Friday
+    fn noon(month: i32, day: i32, weekday: i32) -> Time {
+        Time {
+            year: 2026,
+            month,
+            day,
+            hour: 12,
+            minute: 0,
+            weekday,
+        }
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse_field("*", 0, 59), Ok((1 << 60) - 1));
+        assert_eq!(
+            parse_field("*/15", 0, 59),
+            Ok(1 | 1 << 15 | 1 << 30 | 1 << 45)
+        );
+        assert_eq!(parse_field("1-5", 0, 7), Ok(0b111110));
+        assert_eq!(parse_field("0,30", 0, 59), Ok(1 | 1 << 30));
+        assert_eq!(parse_field("8-18/4", 0, 23), Ok(1 << 8 | 1 << 12 | 1 << 16));
+        // A step after a single value runs to the end
+        assert_eq!(parse_field("50/5", 0, 59), Ok(1 << 50 | 1 << 55));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for field in ["", "60", "5-1", "*/0", "a", "1-", "-1", "1,,2", "*/x"] {
+            assert!(parse_field(field, 0, 59).is_err(), "'{}'", field);
+        }
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("* * * * * *".parse::<Cron>().is_err());
+        assert!("* 24 * * *".parse::<Cron>().is_err());
+        assert!("* * * 13 *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        assert_eq!(cron("0 12 * * 7").weekdays, 1);
+        assert_eq!(cron("0 12 * * 0").weekdays, 1);
+        assert_eq!(cron("0 12 * * 5-7").weekdays, 1 | 1 << 5 | 1 << 6);
+    }
+
+    #[test]
+    fn source_is_normalized() {
+        assert_eq!(cron(" 30  7 * *\t1-5 ").to_string(), "30 7 * * 1-5");
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // Both restricted: either one matching is enough, as in cron
+        let either = cron("0 12 13 * 5");
+        assert!(either.matches_hour(&noon(3, 13, 5)));
+        assert!(either.matches_hour(&noon(2, 13, 5)));
+        assert!(either.matches_hour(&noon(3, 6, 5)));
+        assert!(either.matches_hour(&noon(1, 13, 2)));
+        assert!(!either.matches_hour(&noon(3, 14, 6)));
+
+        // Only one restricted: that one has to match
+        let day = cron("0 12 13 * *");
+        assert!(day.matches_hour(&noon(3, 13, 5)));
+        assert!(!day.matches_hour(&noon(3, 6, 5)));
+        let weekday = cron("0 12 * * 5");
+        assert!(weekday.matches_hour(&noon(3, 6, 5)));
+        assert!(!weekday.matches_hour(&noon(1, 13, 2)));
+
+        // An explicit full range still counts as restricted
+        let every_weekday = cron("0 12 13 * 0-6");
+        assert!(every_weekday.matches_hour(&noon(3, 14, 6)));
+    }
+
+    #[test]
+    fn hours_and_months() {
+        let cron = cron("0 12 * 3 *");
+        assert!(cron.matches_hour(&noon(3, 1, 0)));
+        assert!(!cron.matches_hour(&noon(4, 1, 3)));
+        let mut evening = noon(3, 1, 0);
+        evening.hour = 18;
+        assert!(!cron.matches_hour(&evening));
+    }
+
+    #[test]
+    fn next_run() {
+        let now = current_minute();
+        let schedule = |cron: &str, last_run| Schedule {
+            name: "test".to_string(),
+            cron: cron.parse().unwrap(),
+            request: WakeRequest::All { except: Vec::new() },
+            last_run,
+        };
+        assert_eq!(schedule("* * * * *", 0).next_run(now), Some(now + 1));
+        assert_eq!(schedule("* * * * *", now + 5).next_run(now), Some(now + 6));
+
+        let weekdays = schedule("30 7 * * 1-5", now);
+        let next = weekdays.next_run(now).unwrap();
+        assert!(next > now && next <= now + 4 * 24 * 60);
+        assert!(weekdays.cron.matches(next));
+        assert!((now + 1..next).all(|minute| !weekdays.cron.matches(minute)));
+
+        // February 30th never comes
+        assert_eq!(schedule("0 0 30 2 *", now).next_run(now), None);
+    }
+
+    #[test]
+    fn schedule_round_trip() {
+        let schedule = Schedule {
+            name: "mornings".to_string(),
+            cron: cron("30 7 * * 1-5"),
+            request: WakeRequest::Group {
+                name: "build-farm".to_string(),
+                except: vec!["lab-1".to_string()],
+            },
+            last_run: 29_000_000,
+        };
+        let bytes = schedule.to_bytes();
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(Schedule::read(&mut reader), Ok(schedule));
+        assert!(reader.is_empty());
+        for len in 0..bytes.len() {
+            assert!(Schedule::read(&mut Reader::new(&bytes[..len])).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, "bad/name");
+        write_string(&mut bytes, "* * * * *");
+        assert_eq!(
+            Schedule::read(&mut Reader::new(&bytes)),
+            Err(PacketError::InvalidPayload("invalid schedule name"))
+        );
+
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, "good");
+        write_string(&mut bytes, "* * * *");
+        assert_eq!(
+            Schedule::read(&mut Reader::new(&bytes)),
+            Err(PacketError::InvalidPayload("invalid cron expression"))
+        );
+    }
+
+    #[test]
+    fn format() {
+        let minute = current_minute();
+        let formatted = format_minute(minute);
+        assert_eq!(formatted.len(), "2026-10-18 12:00".len());
+        assert!(formatted.ends_with(&format!("{:02}", local_time(minute).unwrap().minute)));
+    }
+}
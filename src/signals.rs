@@ -1,29 +1,60 @@
-use std::sync::atomic::{AtomicBool, AtomicU32};
+use crate::events::{Event, Subscribers};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Signals {
     run: AtomicBool,
-    update: AtomicBool,
+    // Wakes up whoever is pausing when we exit
+    exiting: (Mutex<()>, Condvar),
+    subscribers: Subscribers,
     is_manager: AtomicBool,
     manager_found: AtomicBool,
     electing: AtomicBool,
     table_version: AtomicU32,
+    term: AtomicU64,
+    members: AtomicU32,
 }
 
 impl Signals {
     pub fn new(start_as_manager: bool) -> Self {
         Self {
             run: AtomicBool::new(true),
-            update: AtomicBool::new(false),
+            exiting: (Mutex::new(()), Condvar::new()),
+            subscribers: Subscribers::default(),
             is_manager: AtomicBool::new(start_as_manager),
             manager_found: AtomicBool::new(false),
             electing: AtomicBool::new(true),
             table_version: AtomicU32::new(0),
+            term: AtomicU64::new(0),
+            members: AtomicU32::new(1),
         }
     }
 
     pub fn exit(&self) {
         self.run.store(false, std::sync::atomic::Ordering::Relaxed);
+        let (lock, exiting) = &self.exiting;
+        let _guard = lock.lock().unwrap();
+        exiting.notify_all();
+    }
+
+    // Sleeps, but no longer than until we exit
+    pub fn pause(&self, duration: Duration) {
+        let (lock, exiting) = &self.exiting;
+        let guard = lock.lock().unwrap();
+        drop(
+            exiting
+                .wait_timeout_while(guard, duration, |_| self.running())
+                .unwrap(),
+        );
+    }
+
+    pub fn wait_for_exit(&self) {
+        let (lock, exiting) = &self.exiting;
+        let guard = lock.lock().unwrap();
+        drop(exiting.wait_while(guard, |_| self.running()).unwrap());
     }
 
     pub fn running(&self) -> bool {
@@ -44,20 +75,16 @@ impl Signals {
             .store(false, std::sync::atomic::Ordering::Relaxed);
     }
 
-    pub fn has_update(&self) -> bool {
-        self.update
-            .compare_exchange(
-                true,
-                false,
-                std::sync::atomic::Ordering::Relaxed,
-                std::sync::atomic::Ordering::Relaxed,
-            )
-            .is_ok()
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.subscribers.subscribe()
     }
 
-    pub fn send_update(&self) {
-        self.update
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+    pub fn add_subscriber(&self, tx: SyncSender<Event>) {
+        self.subscribers.add(tx);
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers.publish(event);
     }
 
     pub fn manager_found(&self) -> bool {
@@ -80,13 +107,25 @@ impl Signals {
     }
 
     pub fn start_election(&self) {
-        self.electing
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        let electing = self
+            .electing
+            .swap(true, std::sync::atomic::Ordering::Relaxed);
+        if !electing {
+            self.publish(Event::ElectionStarted {
+                term: self.current_term(),
+            });
+        }
     }
 
     pub fn end_election(&self) {
-        self.electing
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+        let electing = self
+            .electing
+            .swap(false, std::sync::atomic::Ordering::Relaxed);
+        if electing {
+            self.publish(Event::ElectionEnded {
+                term: self.current_term(),
+            });
+        }
     }
 
     pub fn current_table_version(&self) -> u32 {
@@ -96,11 +135,30 @@ impl Signals {
 
     pub fn update_table_version(&self) -> u32 {
         self.table_version
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1
     }
 
     pub fn overwrite_table_version(&self, version: u32) {
         self.table_version
             .store(version, std::sync::atomic::Ordering::Relaxed);
     }
+
+    pub fn current_term(&self) -> u64 {
+        self.term.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_term(&self, term: u64) {
+        self.term.store(term, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    // Machines in the replicated table, ourselves included
+    pub fn members(&self) -> u32 {
+        self.members.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_members(&self, members: u32) {
+        self.members
+            .store(members, std::sync::atomic::Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,266 @@
+use crate::nodeid::NodeId;
+use crate::packets::{decode_snapshot, encode_snapshot, Reader, Snapshot};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// State file layout:
+// | magic (4) | format version (1) | table version (4) | snapshot | crc32 (4) |
+const STATE_MAGIC: &[u8; 4] = b"WORS";
+const STATE_FORMAT_VERSION: u8 = 8;
+const CRC_SIZE: usize = 4;
+
+// Vote file layout:
+// | magic (4) | format version (1) | term (8) | voted (1) | node id (16) | crc32 (4) |
+const VOTE_MAGIC: &[u8; 4] = b"WORV";
+const VOTE_FORMAT_VERSION: u8 = 2;
+
+pub struct State {
+    pub table_version: u32,
+    pub snapshot: Snapshot,
+}
+
+pub fn load(path: &Path) -> Result<Option<State>, String> {
+    let Some(bytes) = read(path)? else {
+        return Ok(None);
+    };
+    let invalid = |what: &str| format!("Invalid state file {}: {}", path.display(), what);
+    let mut reader = check(&bytes, STATE_MAGIC, STATE_FORMAT_VERSION).map_err(|e| invalid(&e))?;
+    let table_version = reader.u32().map_err(|e| invalid(&e.to_string()))?;
+    let snapshot = decode_snapshot(reader.remaining()).map_err(|e| invalid(&e.to_string()))?;
+
+    Ok(Some(State {
+        table_version,
+        snapshot,
+    }))
+}
+
+fn read(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+// Verifies the checksum and header, returning a reader past the header
+fn check<'a>(bytes: &'a [u8], magic: &[u8], format_version: u8) -> Result<Reader<'a>, String> {
+    if bytes.len() < CRC_SIZE {
+        return Err("too short".to_string());
+    }
+    let (contents, crc) = bytes.split_at(bytes.len() - CRC_SIZE);
+    if crc32fast::hash(contents).to_be_bytes() != crc {
+        return Err("checksum mismatch".to_string());
+    }
+
+    let mut reader = Reader::new(contents);
+    if reader.take(magic.len()).map_err(|e| e.to_string())? != magic {
+        return Err("wrong kind of file".to_string());
+    }
+    let found = reader.u8().map_err(|e| e.to_string())?;
+    if found != format_version {
+        return Err(format!("unsupported format {}", found));
+    }
+    Ok(reader)
+}
+
+pub fn save(path: &Path, table_version: u32, snapshot: &Snapshot) -> std::io::Result<()> {
+    let mut contents = Vec::new();
+    contents.extend(STATE_MAGIC);
+    contents.push(STATE_FORMAT_VERSION);
+    contents.extend(table_version.to_be_bytes());
+    contents.extend(encode_snapshot(snapshot));
+    write(path, contents)
+}
+
+// The last term this node took part in and who it voted for then. Kept so
+// that a restarted node can't vote a second time in the same term.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vote {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+// Kept next to the state file
+pub fn vote_path(state_file: &Path) -> PathBuf {
+    let mut path = state_file.as_os_str().to_owned();
+    path.push(".vote");
+    path.into()
+}
+
+pub fn load_vote(path: &Path) -> Result<Option<Vote>, String> {
+    let Some(bytes) = read(path)? else {
+        return Ok(None);
+    };
+    let invalid = |what: &str| format!("Invalid vote file {}: {}", path.display(), what);
+    let mut reader = check(&bytes, VOTE_MAGIC, VOTE_FORMAT_VERSION).map_err(|e| invalid(&e))?;
+    let term = reader.u64().map_err(|e| invalid(&e.to_string()))?;
+    let voted = reader.u8().map_err(|e| invalid(&e.to_string()))? != 0;
+    let id = reader.node_id().map_err(|e| invalid(&e.to_string()))?;
+
+    Ok(Some(Vote {
+        term,
+        voted_for: voted.then_some(id),
+    }))
+}
+
+pub fn save_vote(path: &Path, vote: &Vote) -> std::io::Result<()> {
+    let mut contents = Vec::new();
+    contents.extend(VOTE_MAGIC);
+    contents.push(VOTE_FORMAT_VERSION);
+    contents.extend(vote.term.to_be_bytes());
+    contents.push(vote.voted_for.is_some() as u8);
+    contents.extend(vote.voted_for.unwrap_or_default().bytes());
+    write(path, contents)
+}
+
+// Written to a temporary file first and renamed over the old one,
+// so a crash never leaves a half written file behind
+fn write(path: &Path, mut contents: Vec<u8>) -> std::io::Result<()> {
+    contents.extend(crc32fast::hash(&contents).to_be_bytes());
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcinfo::{PCInfo, PCStatus};
+    use mac_address::MacAddress;
+
+    // A file of its own in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "wakeonrust-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            pcs: vec![PCInfo::new(
+                NodeId::random(),
+                "lab-1".to_string(),
+                MacAddress::new([0x02, 0, 0, 0, 0, 0x01]),
+                "10.0.0.1".parse().unwrap(),
+                PCStatus::Online,
+                true,
+            )],
+            schedules: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let file = TempFile::new("state");
+        let snapshot = snapshot();
+        save(&file.0, 42, &snapshot).unwrap();
+        let state = load(&file.0).unwrap().unwrap();
+        assert_eq!(state.table_version, 42);
+        assert_eq!(state.snapshot, snapshot);
+    }
+
+    #[test]
+    fn missing_files() {
+        let file = TempFile::new("missing");
+        assert!(load(&file.0).unwrap().is_none());
+        assert_eq!(load_vote(&file.0), Ok(None));
+    }
+
+    #[test]
+    fn corrupt_state() {
+        let file = TempFile::new("corrupt");
+        save(&file.0, 42, &snapshot()).unwrap();
+        let contents = std::fs::read(&file.0).unwrap();
+        for index in 0..contents.len() {
+            let mut corrupt = contents.clone();
+            corrupt[index] ^= 0x01;
+            std::fs::write(&file.0, corrupt).unwrap();
+            assert!(load(&file.0).is_err(), "byte {}", index);
+        }
+    }
+
+    #[test]
+    fn truncated_state() {
+        let file = TempFile::new("truncated");
+        save(&file.0, 42, &snapshot()).unwrap();
+        let contents = std::fs::read(&file.0).unwrap();
+        for len in 0..contents.len() {
+            std::fs::write(&file.0, &contents[..len]).unwrap();
+            assert!(load(&file.0).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn trailing_state() {
+        let file = TempFile::new("trailing");
+        // Valid checksum over a snapshot with a byte too many
+        let mut contents = STATE_MAGIC.to_vec();
+        contents.push(STATE_FORMAT_VERSION);
+        contents.extend(42u32.to_be_bytes());
+        contents.extend(encode_snapshot(&snapshot()));
+        contents.push(0);
+        write(&file.0, contents).unwrap();
+        assert!(load(&file.0).is_err());
+    }
+
+    #[test]
+    fn files_are_told_apart() {
+        let file = TempFile::new("kind");
+        save_vote(&file.0, &Vote::default()).unwrap();
+        assert!(load(&file.0).is_err());
+        save(&file.0, 1, &snapshot()).unwrap();
+        assert!(load_vote(&file.0).is_err());
+    }
+
+    #[test]
+    fn vote_round_trip() {
+        let file = TempFile::new("vote");
+        for vote in [
+            Vote::default(),
+            Vote {
+                term: 7,
+                voted_for: Some(NodeId::random()),
+            },
+        ] {
+            save_vote(&file.0, &vote).unwrap();
+            assert_eq!(load_vote(&file.0), Ok(Some(vote)));
+        }
+    }
+
+    #[test]
+    fn truncated_vote() {
+        let file = TempFile::new("vote-truncated");
+        save_vote(&file.0, &Vote::default()).unwrap();
+        let contents = std::fs::read(&file.0).unwrap();
+        for len in 0..contents.len() {
+            std::fs::write(&file.0, &contents[..len]).unwrap();
+            assert!(load_vote(&file.0).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn vote_path_is_next_to_the_state() {
+        assert_eq!(
+            vote_path(Path::new("/var/lib/wakeonrust.state")),
+            Path::new("/var/lib/wakeonrust.state.vote")
+        );
+    }
+}
@@ -1,124 +1,458 @@
-use std::{net::UdpSocket, time::Instant};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
 
 use crate::{
-    addrs::{ELECTION_ADDR, ELECTION_BROADCAST_ADDR},
-    delays::{CHECK_DELAY, ELECTION_DELAY, MANAGER_TIMEOUT, WAIT_DELAY},
-    packets::{
-        get_packet_type, get_payload, make_header,
-        PacketType::{SselFinPacket, SselGtPacket, SselPacket},
-        BUFFER_SIZE,
-    },
+    config::Config,
+    eventlog,
+    events::Event,
+    netif,
+    nodeid::NodeId,
+    packets::{Authenticator, Message, Socket},
     signals::Signals,
+    state::{self, Vote},
 };
 
-fn elected(signals: &Signals, socket: &UdpSocket) -> bool {
-    // Election variables
-    let our_number = signals.current_table_version();
-    let mut someone_is_greater = false;
-    const MAX_TURNS: u32 = 5;
-    let mut turns_left = MAX_TURNS;
-
-    // Packets
-    let gt_packet = make_header(SselGtPacket, 0);
-    let packet = make_header(SselPacket, our_number.to_be_bytes().len());
-    let mut packet = packet.to_vec();
-    packet.extend_from_slice(&our_number.to_be_bytes());
-
-    while signals.running() && turns_left > 0 {
-        // We check if someone is greater than us
-        if !someone_is_greater {
-            // We send our number to the network
-            socket.send_to(&packet, ELECTION_BROADCAST_ADDR).unwrap();
-        }
-        let current_turn = turns_left;
-        while signals.running() && current_turn == turns_left {
-            // We wait for the response
-            let mut buf = [0; BUFFER_SIZE];
-            match socket.recv_from(&mut buf) {
-                Ok((amt, src)) => {
-                    if let Ok(packe_type) = get_packet_type(&buf[..amt]) {
-                        match packe_type {
-                            SselFinPacket => {
-                                // Election is finished, we wait to find manager
-                                // on another thread
-                                return false; // Exit election
-                            }
-                            SselPacket => {
-                                let msg = get_payload(&buf[..amt]).unwrap();
-                                // Election is still going on
-                                let number = u32::from_be_bytes(msg[..4].try_into().unwrap());
-                                // We compare our number with the received number
-                                if our_number > number {
-                                    // We are greater than the other
-                                    socket.send_to(&gt_packet, src).unwrap();
-                                }
-                            }
-                            SselGtPacket => {
-                                // Someone else is greater than us
-                                // We back off
-                                someone_is_greater = true;
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                Err(_) => {
-                    // No response,
-                    // We decrement the turn counter
-                    turns_left -= 1;
+// Raft style election: a candidate starts a new term, votes for itself and
+// asks everyone else for their vote. Whoever gets a majority of the known
+// members manages the cluster for that term, and since nobody votes twice
+// in a term there is at most one manager per term. The term and our vote
+// are saved next to the state file, so this holds across restarts.
+//
+// Before that, a candidate runs a pre-vote: it asks whether it would win
+// the next term, and only takes that term once a majority says so.
+//
+// The manager holds a lease, renewed by a heartbeat every third of it and
+// counting the acks. Without a majority for a whole lease it steps down.
+// The manager and the participants that heard from it within the lease
+// refuse candidates, so a single flaky node can't depose a working manager.
+//
+// Heartbeats also fence off a second manager, e.g. one that kept a
+// minority partition to itself. The loser steps down and hands its
+// table to the winner, which merges it into its own.
+
+enum Role {
+    Follower,
+    PreCandidate {
+        votes: HashSet<IpAddr>,
+    },
+    Candidate {
+        votes: HashSet<IpAddr>,
+        started: Instant,
+    },
+    Leader {
+        acks: HashSet<IpAddr>,
+        beat: Instant,
+        lease_until: Instant,
+    },
+}
+
+struct Election<'a> {
+    signals: &'a Signals,
+    config: &'a Config,
+    socket: Socket<'a>,
+    // Where a deposed manager sends the address of the one that replaced it
+    deposed: Sender<IpAddr>,
+    our_id: NodeId,
+    role: Role,
+    // Who we voted for in the current term
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    leader_seen: Option<Instant>,
+    // When a follower gives up on the manager, or a candidate on its term
+    deadline: Instant,
+}
+
+impl<'a> Election<'a> {
+    fn new(
+        signals: &'a Signals,
+        config: &'a Config,
+        socket: Socket<'a>,
+        saved_vote: Option<Vote>,
+        deposed: Sender<IpAddr>,
+    ) -> Self {
+        let our_id = config.node_id;
+        let vote = saved_vote.unwrap_or_else(|| reload_vote(signals, config));
+        signals.set_term(vote.term);
+        let mut election = Self {
+            signals,
+            config,
+            socket,
+            deposed,
+            our_id,
+            role: Role::Follower,
+            voted_for: vote.voted_for,
+            leader: None,
+            leader_seen: None,
+            deadline: Instant::now(),
+        };
+        election.reset_deadline();
+        election
+    }
+
+    // Randomized so that candidates rarely split the vote
+    fn reset_deadline(&mut self) {
+        let lease = self.config.lease();
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..lease);
+        self.deadline = Instant::now() + lease + jitter;
+    }
+
+    // Over every known member, a manager that went silent included, so
+    // that the two sides of a split can't both reach it
+    fn majority(&self) -> usize {
+        self.signals.members().max(1) as usize / 2 + 1
+    }
+
+    fn leader_alive(&self) -> bool {
+        self.leader_seen
+            .is_some_and(|t| t.elapsed() < self.config.lease())
+    }
+
+    fn lease_valid(&self) -> bool {
+        match self.role {
+            Role::Leader { lease_until, .. } => Instant::now() < lease_until,
+            _ => false,
+        }
+    }
+
+    fn become_follower(&mut self) {
+        if let Role::Leader { .. } = self.role {
+            self.leader = None;
+            self.signals.relinquish_management();
+            self.signals.lost_manager();
+            self.signals.publish(Event::ManagerChanged(None));
+        }
+        self.role = Role::Follower;
+        self.reset_deadline();
+    }
+
+    // Called before anyone hears of the new term or vote
+    fn save_vote(&self) {
+        if !self.config.persist_state {
+            return;
+        }
+        let path = state::vote_path(&self.config.state_file);
+        let vote = Vote {
+            term: self.signals.current_term(),
+            voted_for: self.voted_for,
+        };
+        if let Err(e) = state::save_vote(&path, &vote) {
+            let event = format!("Failed to save {}: {}", path.display(), e);
+            eventlog::report(self.signals, self.config, &event);
+        }
+    }
+
+    fn adopt_term(&mut self, term: u64) {
+        if term > self.signals.current_term() {
+            self.signals.set_term(term);
+            self.voted_for = None;
+            self.save_vote();
+            self.become_follower();
+        }
+    }
+
+    fn send(&self, message: &Message, addr: SocketAddr) {
+        self.socket.send_to(message, addr).unwrap();
+    }
+
+    fn heartbeat(&mut self, addr: SocketAddr) {
+        let heartbeat = Message::Heartbeat {
+            term: self.signals.current_term(),
+            leader: self.our_id,
+            table_version: self.signals.current_table_version(),
+        };
+        self.send(&heartbeat, addr);
+    }
+
+    fn pre_campaign(&mut self) {
+        self.role = Role::PreCandidate {
+            votes: HashSet::new(),
+        };
+        self.reset_deadline();
+
+        let request = Message::Election {
+            term: self.signals.current_term() + 1,
+            candidate: self.our_id,
+            table_version: self.signals.current_table_version(),
+            pre_vote: true,
+        };
+        self.send(&request, self.config.election_broadcast_addr());
+        self.count_pre_votes();
+    }
+
+    fn count_pre_votes(&mut self) {
+        match &self.role {
+            Role::PreCandidate { votes } if votes.len() + 1 >= self.majority() => self.campaign(),
+            _ => {}
+        }
+    }
+
+    fn campaign(&mut self) {
+        let term = self.signals.current_term() + 1;
+        self.signals.set_term(term);
+        self.signals.start_election();
+        self.voted_for = Some(self.our_id);
+        self.save_vote();
+        self.role = Role::Candidate {
+            votes: HashSet::new(),
+            started: Instant::now(),
+        };
+        self.reset_deadline();
+
+        let request = Message::Election {
+            term,
+            candidate: self.our_id,
+            table_version: self.signals.current_table_version(),
+            pre_vote: false,
+        };
+        self.send(&request, self.config.election_broadcast_addr());
+        // Alone in the cluster, our own vote is enough
+        self.count_votes();
+    }
+
+    fn count_votes(&mut self) {
+        let (votes, started) = match &self.role {
+            Role::Candidate { votes, started } => (votes.len() + 1, *started),
+            _ => return,
+        };
+        if votes < self.majority() {
+            return;
+        }
+
+        // Votes count as acks for the first lease
+        self.role = Role::Leader {
+            acks: HashSet::new(),
+            beat: Instant::now(),
+            lease_until: started + self.config.lease(),
+        };
+        self.leader = Some(self.our_id);
+        self.leader_seen = None;
+        self.signals.i_am_manager();
+        self.signals.end_election();
+        self.signals
+            .publish(Event::ManagerChanged(Some(self.our_id)));
+        self.heartbeat(self.config.election_broadcast_addr());
+    }
+
+    fn on_request(
+        &mut self,
+        term: u64,
+        candidate: NodeId,
+        table_version: u32,
+        pre_vote: bool,
+        src: SocketAddr,
+    ) {
+        if candidate == self.our_id {
+            return; // Our own broadcast
+        }
+        if self.lease_valid() {
+            // A majority still follows us, so nobody can win that term.
+            // Refuse without taking it and let the candidate know.
+            self.heartbeat(src);
+            return;
+        }
+        if self.leader_alive() {
+            return;
+        }
+
+        // Ties on the table version are broken by the id
+        let up_to_date =
+            (table_version, candidate) >= (self.signals.current_table_version(), self.our_id);
+        if pre_vote {
+            // Changes nothing here, whatever the answer
+            let vote = Message::Vote {
+                term,
+                granted: term > self.signals.current_term() && up_to_date,
+                pre_vote: true,
+            };
+            self.send(&vote, src);
+            return;
+        }
+
+        self.adopt_term(term);
+        let current_term = self.signals.current_term();
+        let granted =
+            term == current_term && self.voted_for.is_none_or(|id| id == candidate) && up_to_date;
+        if granted {
+            self.voted_for = Some(candidate);
+            self.save_vote();
+            self.reset_deadline();
+        }
+        let vote = Message::Vote {
+            term: current_term,
+            granted,
+            pre_vote: false,
+        };
+        self.send(&vote, src);
+    }
+
+    fn on_vote(&mut self, term: u64, granted: bool, pre_vote: bool, src: SocketAddr) {
+        if pre_vote {
+            if let Role::PreCandidate { votes } = &mut self.role {
+                if granted && term == self.signals.current_term() + 1 {
+                    votes.insert(src.ip());
+                    self.count_pre_votes();
                 }
             }
+            return;
+        }
+        if term > self.signals.current_term() {
+            self.adopt_term(term);
+            return;
+        }
+        if term < self.signals.current_term() || !granted {
+            return;
+        }
+        match &mut self.role {
+            Role::Candidate { votes, .. } => {
+                votes.insert(src.ip());
+                self.count_votes();
+            }
+            Role::Leader { acks, .. } => {
+                acks.insert(src.ip());
+            }
+            Role::Follower | Role::PreCandidate { .. } => {}
+        }
+    }
+
+    fn on_heartbeat(&mut self, term: u64, leader: NodeId, table_version: u32, src: SocketAddr) {
+        if leader == self.our_id {
+            return; // Our own broadcast
+        }
+        if let Role::Leader { .. } = self.role {
+            // Two managers, usually after a partition healed. The later
+            // term wins, then the newer table, then the greater id
+            let ours = (
+                self.signals.current_term(),
+                self.signals.current_table_version(),
+                self.our_id,
+            );
+            if (term, table_version, leader) < ours {
+                self.heartbeat(src);
+                return;
+            }
+            // Hand our table over so the winner can merge it
+            self.deposed.send(src.ip()).unwrap();
+            self.become_follower();
+        }
+        if term < self.signals.current_term() {
+            // A manager from an older term, the current one will depose it
+            return;
+        }
+        self.adopt_term(term);
+
+        // No longer a candidate either
+        self.role = Role::Follower;
+        self.signals.end_election();
+        if self.leader != Some(leader) {
+            // Let discovery find the new manager
+            self.leader = Some(leader);
+            self.signals.lost_manager();
+            self.signals.publish(Event::ManagerChanged(Some(leader)));
+        }
+        self.leader_seen = Some(Instant::now());
+        self.reset_deadline();
+        let ack = Message::Vote {
+            term,
+            granted: true,
+            pre_vote: false,
+        };
+        self.send(&ack, src);
+    }
+
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let majority = self.majority();
+        let lease = self.config.lease();
+        let Role::Leader {
+            acks,
+            beat,
+            lease_until,
+        } = &mut self.role
+        else {
+            if now >= self.deadline {
+                self.pre_campaign();
+            }
+            return;
+        };
+
+        if acks.len() + 1 >= majority {
+            *lease_until = (*beat + lease).max(*lease_until);
+        }
+        if now >= *lease_until {
+            // Lost the majority, someone else may take over
+            self.become_follower();
+        } else if now >= *beat + lease / 3 {
+            acks.clear();
+            *beat = now;
+            self.heartbeat(self.config.election_broadcast_addr());
+        }
+    }
+
+    fn handle(&mut self, message: Message, src: SocketAddr) {
+        match message {
+            Message::Election {
+                term,
+                candidate,
+                table_version,
+                pre_vote,
+            } => self.on_request(term, candidate, table_version, pre_vote, src),
+            Message::Vote {
+                term,
+                granted,
+                pre_vote,
+            } => self.on_vote(term, granted, pre_vote, src),
+            Message::Heartbeat {
+                term,
+                leader,
+                table_version,
+            } => self.on_heartbeat(term, leader, table_version, src),
+            _ => {}
         }
     }
-    !someone_is_greater
 }
 
-pub fn initialize(signals: &Signals) {
-    let socket = UdpSocket::bind(ELECTION_ADDR).unwrap();
-    socket.set_read_timeout(Some(ELECTION_DELAY)).unwrap();
-    socket.set_broadcast(true).unwrap();
+// Starting again after a crash, from the vote we saved before it
+fn reload_vote(signals: &Signals, config: &Config) -> Vote {
+    if !config.persist_state {
+        return Vote::default();
+    }
+    match state::load_vote(&state::vote_path(&config.state_file)) {
+        Ok(vote) => vote.unwrap_or_default(),
+        Err(e) => {
+            eventlog::report(signals, config, &e);
+            Vote::default()
+        }
+    }
+}
 
-    // Packets
-    let finished_packet = make_header(SselFinPacket, 0);
-    let mut last_seen = Instant::now();
+pub fn initialize(
+    signals: &Signals,
+    config: &Config,
+    auth: &Authenticator,
+    saved_vote: Option<Vote>,
+    deposed_tx: Sender<IpAddr>,
+) {
+    let socket = Socket::new(
+        netif::bind(config, config.election_addr()).unwrap(),
+        config.cluster_id,
+        auth,
+    );
+    socket
+        .set_read_timeout(Some(config.election_delay()))
+        .unwrap();
+    netif::join(&socket, config, config.election_broadcast_addr()).unwrap();
 
+    let mut election = Election::new(signals, config, socket, saved_vote, deposed_tx);
     while signals.running() {
-        if signals.is_manager() {
-            if last_seen.elapsed() >= MANAGER_TIMEOUT {
-                signals.relinquish_management();
-            }
-            last_seen = Instant::now();
-            // We respond to election packets with a finished packet
-            let mut buf = [0; BUFFER_SIZE];
-            match socket.recv_from(&mut buf) {
-                Ok((amt, src)) => {
-                    if let Ok(packe_type) = get_packet_type(&buf[..amt]) {
-                        match packe_type {
-                            SselPacket => {
-                                socket.send_to(&finished_packet, src).unwrap();
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                Err(_) => {}
-            }
-        } else if !signals.manager_found() {
-            signals.start_election();
-            // We start the election
-            let has_been_elected = elected(signals, &socket);
-            signals.end_election();
-
-            if has_been_elected {
-                signals.i_am_manager();
-                signals.send_update();
-                last_seen = Instant::now();
-            } else {
-                std::thread::sleep(WAIT_DELAY);
-            }
-        } else {
-            // We found the manager
-            std::thread::sleep(CHECK_DELAY);
+        if let Ok((message, src)) = election.socket.recv_from() {
+            election.handle(message, src);
         }
+        election.tick();
     }
 }
@@ -1,112 +1,547 @@
 use crate::{
-    addrs::{WAKEUP_ADDR, WAKEUP_SEND_ADDR},
-    delays::CHECK_DELAY,
-    packets::make_wakeup_packet,
-    pcinfo::{PCInfo, PCStatus},
+    config::Config,
+    eventlog,
+    events::Event,
+    netif,
+    node::NodeError,
+    nodeid::NodeId,
+    packets::{Authenticator, Message, SleepAction, Socket},
+    pcinfo::{PCInfo, PCStatus, WakeRequest, WakeSetting},
+    pctable::PcTable,
     signals::Signals,
+    wol::send_wakeup,
 };
+use gethostname::gethostname;
+use mac_address::MacAddress;
 use std::net::UdpSocket;
-use std::sync::{mpsc::Receiver, Mutex};
-use std::{collections::HashMap, sync::mpsc::Sender};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::mpsc::Sender,
+};
 
 use super::replication::UpdateType;
 
+// A wakeup followed until the PC comes online or the timeout passes
+struct WakeJob {
+    id: NodeId,
+    hostname: String,
+    started: Instant,
+    tries: u32,
+    retry: Duration,
+    next_try: Instant,
+}
+
+// The PC the user means, which has to be the only one going by that name
+pub(crate) fn find_pc(pc_table: &PcTable, name: &str) -> Result<PCInfo, NodeError> {
+    let mut pcs = pc_table.find(name);
+    if pcs.len() > 1 {
+        let ids = pcs.iter().map(|pc_info| pc_info.get_id().short()).collect();
+        return Err(NodeError::AmbiguousPc {
+            name: name.to_string(),
+            ids,
+        });
+    }
+    pcs.pop()
+        .ok_or_else(|| NodeError::PcNotFound(name.to_string()))
+}
+
+// Node checked the name already, but the table may have changed since
+fn find_pc_or_report(
+    signals: &Signals,
+    config: &Config,
+    pc_table: &PcTable,
+    name: &str,
+) -> Option<PCInfo> {
+    find_pc(pc_table, name)
+        .map_err(|e| eventlog::report(signals, config, &e.to_string()))
+        .ok()
+}
+
+// Queues the PCs a request is about, bulk requests only pick the sleeping
+// ones that aren't being woken up already
+fn queue_request(
+    signals: &Signals,
+    config: &Config,
+    pc_table: &PcTable,
+    jobs: &[WakeJob],
+    pending: &mut VecDeque<NodeId>,
+    request: WakeRequest,
+) {
+    let (group, except) = match request {
+        WakeRequest::Host(name) => {
+            if let Some(pc_info) = find_pc_or_report(signals, config, pc_table, &name) {
+                if !pending.contains(pc_info.get_id()) {
+                    pending.push_back(*pc_info.get_id());
+                }
+            }
+            return;
+        }
+        WakeRequest::Group { name, except } => (Some(name), except),
+        WakeRequest::All { except } => (None, except),
+    };
+
+    let pcs = pc_table.snapshot();
+    if let Some(name) = &group {
+        if !pcs.iter().any(|pc_info| pc_info.in_group(name)) {
+            let e = NodeError::GroupNotFound(name.clone());
+            return eventlog::report(signals, config, &e.to_string());
+        }
+    }
+    let mut sleeping = pcs
+        .iter()
+        .filter(|pc_info| group.as_ref().is_none_or(|name| pc_info.in_group(name)))
+        .filter(|pc_info| *pc_info.get_status() == PCStatus::Offline)
+        .filter(|pc_info| !except.iter().any(|name| pc_info.is_called(name)))
+        .filter(|pc_info| !jobs.iter().any(|job| job.id == *pc_info.get_id()))
+        .filter(|pc_info| !pending.contains(pc_info.get_id()))
+        .collect::<Vec<_>>();
+    sleeping.sort_by_key(|pc_info| pc_info.get_hostname());
+
+    let what = match &group {
+        Some(name) => format!("in {}", name),
+        None => "in the cluster".to_string(),
+    };
+    if sleeping.is_empty() {
+        return eventlog::report(signals, config, &format!("No sleeping PC {}", what));
+    }
+    eventlog::report(
+        signals,
+        config,
+        &format!("Waking up {} PCs {}", sleeping.len(), what),
+    );
+    pending.extend(sleeping.iter().map(|pc_info| *pc_info.get_id()));
+}
+
+// Returns whether a wakeup was sent
+fn start_job(
+    signals: &Signals,
+    config: &Config,
+    socket: &UdpSocket,
+    pc_table: &PcTable,
+    jobs: &mut Vec<WakeJob>,
+    id: NodeId,
+) -> bool {
+    // Left the table while queued
+    let Some(pc_info) = pc_table.get(&id) else {
+        return false;
+    };
+    let hostname = pc_info.get_hostname().clone();
+    if jobs.iter().any(|job| job.id == id) {
+        eventlog::report(signals, config, &format!("Already waking up {}", hostname));
+        return false;
+    }
+    if *pc_info.get_status() != PCStatus::Offline {
+        eventlog::report(signals, config, &format!("{} is not sleeping", hostname));
+        return false;
+    }
+    match send_wakeup(config, socket, &pc_info) {
+        Ok(wake_target) => {
+            if wake_target.via != pc_info.get_wake_target().via {
+                eventlog::report(
+                    signals,
+                    config,
+                    "No permission for raw Ethernet, falling back to UDP",
+                );
+            }
+            signals.publish(Event::WakeSent {
+                hostname: hostname.clone(),
+                target: wake_target,
+                attempt: 1,
+            });
+            eventlog::report(
+                signals,
+                config,
+                &format!("Waking up {} through {}", hostname, wake_target),
+            );
+            let now = Instant::now();
+            jobs.push(WakeJob {
+                id,
+                hostname,
+                started: now,
+                tries: 1,
+                retry: config.wake_retry(),
+                next_try: now + config.wake_retry(),
+            });
+            true
+        }
+        Err(e) => {
+            eventlog::report(
+                signals,
+                config,
+                &format!("Failed to wake up {}: {}", hostname, e),
+            );
+            false
+        }
+    }
+}
+
+// Resends wakeups that haven't worked yet, dropping the jobs that are over
+fn follow_jobs(
+    signals: &Signals,
+    config: &Config,
+    socket: &UdpSocket,
+    pc_table: &PcTable,
+    jobs: &mut Vec<WakeJob>,
+) {
+    let pcs = pc_table.snapshot();
+    jobs.retain_mut(|job| {
+        let elapsed = job.started.elapsed();
+        let Some(pc_info) = pcs.get(&job.id) else {
+            eventlog::report(
+                signals,
+                config,
+                &format!("Stopped waking up {}, it left the table", job.hostname),
+            );
+            return false;
+        };
+        if *pc_info.get_status() == PCStatus::Online {
+            eventlog::report(
+                signals,
+                config,
+                &format!(
+                    "{} is up after {:.1}s and {} tries",
+                    job.hostname,
+                    elapsed.as_secs_f64(),
+                    job.tries
+                ),
+            );
+            return false;
+        }
+        if !signals.is_manager() {
+            eventlog::report(
+                signals,
+                config,
+                &format!("Stopped waking up {}, no longer the manager", job.hostname),
+            );
+            return false;
+        }
+        if elapsed >= config.wake_timeout() {
+            eventlog::report(
+                signals,
+                config,
+                &format!(
+                    "Failed to wake up {} within {}s, tried {} times",
+                    job.hostname,
+                    config.wake_timeout().as_secs(),
+                    job.tries
+                ),
+            );
+            return false;
+        }
+
+        if Instant::now() >= job.next_try {
+            job.tries += 1;
+            match send_wakeup(config, socket, pc_info) {
+                Ok(wake_target) => signals.publish(Event::WakeSent {
+                    hostname: job.hostname.clone(),
+                    target: wake_target,
+                    attempt: job.tries,
+                }),
+                Err(e) => eventlog::report(
+                    signals,
+                    config,
+                    &format!("Failed to resend wakeup to {}: {}", job.hostname, e),
+                ),
+            }
+            job.retry = (job.retry * 2).min(config.wake_retry_max());
+            job.next_try = Instant::now() + job.retry;
+        }
+        true
+    });
+}
+
 pub fn wakeup(
     signals: &Signals,
-    m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-    wake_rx: Receiver<String>,
+    config: &Config,
+    pc_table: &PcTable,
+    wake_rx: &Receiver<WakeRequest>,
 ) {
-    let socket = UdpSocket::bind(WAKEUP_SEND_ADDR).unwrap();
+    let socket = netif::bind(config, config.wakeup_send_addr()).unwrap();
     socket.set_broadcast(true).unwrap();
+    let mut jobs = Vec::new();
+    // PCs waiting for their first wakeup, started one at a time so that a
+    // whole room booting at once doesn't trip the breaker
+    let mut pending = VecDeque::new();
+    let mut last_start: Option<Instant> = None;
 
     while signals.running() {
-        match wake_rx.try_recv() {
-            Ok(hostname) => {
-                let pc_map = m_pc_map.lock().unwrap();
-                if let Some(pc_info) = pc_map.get(&hostname) {
-                    if *pc_info.get_status() == PCStatus::Offline {
-                        let wakeup_packet = make_wakeup_packet(pc_info.get_mac());
-                        socket.send_to(&wakeup_packet, WAKEUP_ADDR).unwrap();
-                        println!("Waking up {}", hostname);
-                    } else {
-                        println!("{} is not sleeping", hostname);
+        match wake_rx.recv_timeout(config.check_delay()) {
+            Ok(request) => queue_request(signals, config, pc_table, &jobs, &mut pending, request),
+
+            Err(_) => {
+                if !signals.is_manager() && !pending.is_empty() {
+                    eventlog::report(
+                        signals,
+                        config,
+                        &format!(
+                            "Dropped {} queued wakeups, no longer the manager",
+                            pending.len()
+                        ),
+                    );
+                    pending.clear();
+                }
+                if last_start.is_none_or(|t| t.elapsed() >= config.wake_interval()) {
+                    while let Some(id) = pending.pop_front() {
+                        if start_job(signals, config, &socket, pc_table, &mut jobs, id) {
+                            last_start = Some(Instant::now());
+                            break;
+                        }
                     }
-                } else {
-                    println!("PC not found");
                 }
+                follow_jobs(signals, config, &socket, pc_table, &mut jobs);
             }
+        }
+    }
+}
 
-            Err(_) => {
-                std::thread::sleep(CHECK_DELAY);
+// Machines with the same id overwrite each other in the table, taking
+// turns as they rejoin, the same hostname only makes them harder to tell
+// apart
+fn report_duplicates(
+    signals: &Signals,
+    config: &Config,
+    pc_table: &PcTable,
+    pc_info: &PCInfo,
+    our_hostname: &str,
+    reported: &mut HashSet<(MacAddress, MacAddress)>,
+) {
+    let pcs = pc_table.snapshot();
+    let duplicate = match pcs.get(pc_info.get_id()) {
+        Some(known) if known.get_mac() != pc_info.get_mac() => {
+            let mut macs = (*known.get_mac(), *pc_info.get_mac());
+            if macs.0 > macs.1 {
+                macs = (macs.1, macs.0);
+            }
+            if !reported.insert(macs) {
+                return;
             }
+            format!(
+                "{} ({}) and {} ({}) have the same id {}, remove the id file of one of them",
+                known.get_hostname(),
+                known.get_mac(),
+                pc_info.get_hostname(),
+                pc_info.get_mac(),
+                pc_info.get_id()
+            )
         }
-    }
+        Some(_) => return,
+        None => {
+            let taken = pc_info.get_hostname() == our_hostname
+                || pcs
+                    .iter()
+                    .any(|known| known.get_hostname() == pc_info.get_hostname());
+            if !taken {
+                return;
+            }
+            format!(
+                "{} joined with a hostname already in the cluster, its id is {}",
+                pc_info.get_hostname(),
+                pc_info.get_id().short()
+            )
+        }
+    };
+    eventlog::report(signals, config, &duplicate);
 }
 
 pub fn add_pcs(
     signals: &Signals,
-    m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-    new_pc_rx: Receiver<PCInfo>,
+    config: &Config,
+    pc_table: &PcTable,
+    new_pc_rx: &Receiver<PCInfo>,
     rb_update_tx: Sender<(UpdateType, PCInfo)>,
 ) {
+    let our_hostname = gethostname().into_string().unwrap();
+    let mut reported = HashSet::new();
     while signals.running() {
-        match new_pc_rx.try_recv() {
-            Ok(pc_info) => {
-                let mut pc_map = m_pc_map.lock().unwrap();
-                pc_map.insert(pc_info.get_hostname().clone(), pc_info.clone());
-                rb_update_tx.send((UpdateType::Add, pc_info)).unwrap();
-                signals.send_update();
-            }
-            Err(_) => {
-                std::thread::sleep(CHECK_DELAY);
-            }
+        let Ok(pc_info) = new_pc_rx.recv_timeout(config.check_delay()) else {
+            continue;
+        };
+        report_duplicates(
+            signals,
+            config,
+            pc_table,
+            &pc_info,
+            &our_hostname,
+            &mut reported,
+        );
+        // Nothing to replicate after a repeated discovery
+        if let Some(pc_info) = pc_table.add(pc_info) {
+            rb_update_tx.send((UpdateType::Add, pc_info)).unwrap();
         }
     }
 }
 
 pub fn update_statuses(
     signals: &Signals,
-    m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-    sleep_status_rx: Receiver<(String, PCStatus)>,
+    config: &Config,
+    pc_table: &PcTable,
+    sleep_status_rx: &Receiver<(NodeId, PCStatus)>,
     rb_update_tx: Sender<(UpdateType, PCInfo)>,
 ) {
     while signals.running() {
-        match sleep_status_rx.try_recv() {
-            Ok((hostname, status)) => {
-                let mut pc_map = m_pc_map.lock().unwrap();
-                if let Some(pc_info) = pc_map.get_mut(&hostname) {
-                    pc_info.set_status(status);
-                    rb_update_tx
-                        .send((UpdateType::Change, pc_info.clone()))
-                        .unwrap();
-                    signals.send_update();
-                }
-            }
-            Err(_) => {
-                std::thread::sleep(CHECK_DELAY);
-            }
+        let Ok((id, status)) = sleep_status_rx.recv_timeout(config.check_delay()) else {
+            continue;
+        };
+        if let Some(pc_info) = pc_table.set_status(&id, status) {
+            rb_update_tx.send((UpdateType::Change, pc_info)).unwrap();
         }
     }
 }
 
 pub fn remove_pcs(
     signals: &Signals,
-    m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-    remove_rx: Receiver<String>,
+    config: &Config,
+    pc_table: &PcTable,
+    remove_rx: &Receiver<NodeId>,
+    rb_update_tx: Sender<(UpdateType, PCInfo)>,
+) {
+    while signals.running() {
+        let Ok(id) = remove_rx.recv_timeout(config.check_delay()) else {
+            continue;
+        };
+        if let Some(pc_info) = pc_table.remove(&id) {
+            rb_update_tx.send((UpdateType::Remove, pc_info)).unwrap();
+        }
+    }
+}
+
+pub fn set_wake_settings(
+    signals: &Signals,
+    config: &Config,
+    pc_table: &PcTable,
+    setting_rx: &Receiver<(String, WakeSetting)>,
     rb_update_tx: Sender<(UpdateType, PCInfo)>,
 ) {
     while signals.running() {
-        match remove_rx.try_recv() {
-            Ok(hostname) => {
-                let mut pc_map = m_pc_map.lock().unwrap();
-                if let Some(pc_info) = pc_map.remove(&hostname) {
-                    rb_update_tx.send((UpdateType::Remove, pc_info)).unwrap();
-                    signals.send_update();
+        let Ok((name, setting)) = setting_rx.recv_timeout(config.check_delay()) else {
+            continue;
+        };
+        let Some(pc_info) = find_pc_or_report(signals, config, pc_table, &name) else {
+            continue;
+        };
+        let hostname = pc_info.get_hostname();
+        let mut outcome = String::new();
+        let changed = pc_table.change(pc_info.get_id(), |pc_info| match setting {
+            WakeSetting::Target(wake_target) => {
+                pc_info.set_wake_target(wake_target);
+                outcome = format!("{} will be woken up through {}", hostname, wake_target);
+                true
+            }
+            WakeSetting::SecureOn(secure_on) => {
+                outcome = match secure_on {
+                    Some(_) => format!("SecureOn password set for {}", hostname),
+                    None => format!("SecureOn password cleared for {}", hostname),
+                };
+                pc_info.set_secure_on(secure_on);
+                true
+            }
+            WakeSetting::JoinGroup(group) => {
+                if !pc_info.join_group(&group) {
+                    outcome = format!("{} can't join {}", hostname, group);
+                    return false;
                 }
+                outcome = format!("{} joined {}", hostname, group);
+                true
             }
-            Err(_) => {
-                std::thread::sleep(CHECK_DELAY);
+            WakeSetting::LeaveGroup(group) => {
+                if !pc_info.leave_group(&group) {
+                    outcome = format!("{} is not in {}", hostname, group);
+                    return false;
+                }
+                outcome = format!("{} left {}", hostname, group);
+                true
+            }
+        });
+        if !outcome.is_empty() {
+            eventlog::report(signals, config, &outcome);
+        }
+        if let Some(pc_info) = changed {
+            rb_update_tx.send((UpdateType::Change, pc_info)).unwrap();
+        }
+    }
+}
+
+// How many times the manager asks before giving up on a participant
+const SLEEP_TRIES: usize = 3;
+
+fn request_sleep(
+    signals: &Signals,
+    config: &Config,
+    socket: &Socket,
+    pc_table: &PcTable,
+    name: &str,
+    action: SleepAction,
+) {
+    let Some(pc_info) = find_pc_or_report(signals, config, pc_table, name) else {
+        return;
+    };
+    let hostname = pc_info.get_hostname();
+    if !pc_info.is_online() {
+        return eventlog::report(
+            signals,
+            config,
+            &format!("{} is already sleeping", hostname),
+        );
+    }
+    let ip = *pc_info.get_ip();
+    let id = rand::random();
+    let request = Message::Sleep { id, action };
+    let addr = config.peer_addr(ip, config.monitor_port);
+    for _ in 0..SLEEP_TRIES {
+        if !signals.running() {
+            return;
+        }
+        socket.send_to(&request, addr).unwrap();
+        let deadline = Instant::now() + config.wait_delay();
+        while Instant::now() < deadline {
+            let Ok((message, src)) = socket.recv_from() else {
+                break;
+            };
+            let Message::SleepAck {
+                id: acked,
+                accepted,
+            } = message
+            else {
+                continue;
+            };
+            if acked != id || src.ip() != ip {
+                continue; // Late answer to an earlier request
             }
+            let event = if accepted {
+                format!("{} agreed to {}", hostname, action)
+            } else {
+                format!("{} refused to {}", hostname, action)
+            };
+            return eventlog::report(signals, config, &event);
         }
     }
+    eventlog::report(
+        signals,
+        config,
+        &format!("{} didn't answer the request to {}", hostname, action),
+    );
+}
+
+pub fn put_to_sleep(
+    signals: &Signals,
+    config: &Config,
+    auth: &Authenticator,
+    pc_table: &PcTable,
+    sleep_rx: &Receiver<(String, SleepAction)>,
+) {
+    let socket = Socket::new(
+        netif::bind(config, config.sleep_send_addr()).unwrap(),
+        config.cluster_id,
+        auth,
+    );
+    socket.set_read_timeout(Some(config.wait_delay())).unwrap();
+
+    while signals.running() {
+        let Ok((name, action)) = sleep_rx.recv_timeout(config.check_delay()) else {
+            continue;
+        };
+        request_sleep(signals, config, &socket, pc_table, &name, action)
+    }
 }
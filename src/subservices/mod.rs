@@ -1,6 +1,6 @@
-pub mod interface;
 pub mod discovery;
-pub mod monitoring;
+pub mod election;
 pub mod management;
+pub mod monitoring;
 pub mod replication;
-pub mod election;
\ No newline at end of file
+pub mod scheduler;
@@ -1,30 +1,24 @@
-use crate::delays::{CHECK_DELAY, WAIT_DELAY};
-use crate::packets::{
-    check_packet, make_header,
-    PacketType::{SsrAckPacket, SsrPacket},
-    BUFFER_SIZE,
-};
-use crate::pcinfo::{PCInfo, PCStatus};
+use crate::config::Config;
+use crate::nodeid::NodeId;
+use crate::packets::{Authenticator, Message, SleepAction, Socket};
+use crate::pcinfo::PCStatus;
+use crate::pctable::PcTable;
 use crate::signals::Signals;
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::sync::{mpsc::Sender, Mutex};
+use crate::{eventlog, netif};
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
 
 pub mod status {
+    use std::process::{Child, Command};
     use std::time::Instant;
 
     use super::*;
-    use crate::{
-        addrs::{MONITOR_ADDR, MONITOR_PORT},
-        delays::MANAGER_TIMEOUT,
-    };
 
-    fn response_from_client(signals: &Signals, socket: &UdpSocket, ip: &IpAddr) -> PCStatus {
+    fn response_from_client(signals: &Signals, socket: &Socket, ip: &IpAddr) -> PCStatus {
         while signals.running() {
-            let mut buf = [0; BUFFER_SIZE];
-            match socket.recv_from(&mut buf) {
-                Ok((amt, src)) => {
-                    if check_packet(&buf[..amt], SsrAckPacket).is_err() {
+            match socket.recv_from() {
+                Ok((message, src)) => {
+                    if message != Message::StatusAck {
                         continue; // Ignore invalid packets
                     }
                     if src.ip() != *ip {
@@ -42,22 +36,23 @@ pub mod status {
 
     fn listen_for_clients(
         signals: &Signals,
-        socket: &UdpSocket,
-        pcs: Vec<(&String, &IpAddr, &PCStatus)>,
-        sleep_status: &Sender<(String, PCStatus)>,
+        config: &Config,
+        socket: &Socket,
+        pcs: Vec<(&NodeId, &IpAddr, &PCStatus)>,
+        sleep_status: &Sender<(NodeId, PCStatus)>,
     ) {
-        let ssr = make_header(SsrPacket, 0);
-        for (hostname, ip, status) in pcs {
+        let ssr = Message::Status;
+        for (id, ip, status) in pcs {
             if !signals.running() {
                 break;
             }
-            let addr = SocketAddr::new(*ip, MONITOR_PORT);
+            let addr = config.peer_addr(*ip, config.monitor_port);
             let mut tries = 2;
             let new_status = loop {
                 socket
                     .send_to(&ssr, addr)
                     .expect("Failed to send to client");
-                match response_from_client(&signals, &socket, ip) {
+                match response_from_client(signals, socket, ip) {
                     PCStatus::Online => break PCStatus::Online,
                     PCStatus::Offline => {
                         if tries == 0 {
@@ -70,92 +65,179 @@ pub mod status {
             if new_status == *status {
                 continue;
             }
-            sleep_status.send((hostname.clone(), new_status)).unwrap();
+            sleep_status.send((*id, new_status)).unwrap();
         }
     }
 
+    // The command to run when asked to sleep, or why we won't
+    fn sleep_command<'a>(
+        config: &'a Config,
+        auth: &Authenticator,
+        pc_table: &PcTable,
+        action: SleepAction,
+        src: &IpAddr,
+    ) -> Result<&'a str, &'static str> {
+        // Without a secret anyone on the network could put us to sleep
+        if !auth.is_keyed() {
+            return Err("the cluster has no secret");
+        }
+        let from_manager = pc_table
+            .by_ip(src)
+            .is_some_and(|pc_info| pc_info.is_manager());
+        if !from_manager {
+            return Err("the request didn't come from the manager");
+        }
+        let command = match action {
+            SleepAction::Suspend => &config.suspend_command,
+            SleepAction::PowerOff => &config.poweroff_command,
+        };
+        command.as_deref().ok_or("no command is configured for it")
+    }
+
+    // Not waited for, the ack has to go out right away
+    fn run_sleep_command(
+        signals: &Signals,
+        config: &Config,
+        action: SleepAction,
+        command: &str,
+    ) -> Option<(String, Child)> {
+        eventlog::report(
+            signals,
+            config,
+            &format!("Going to {} at the manager's request", action),
+        );
+        match Command::new("sh").arg("-c").arg(command).spawn() {
+            Ok(child) => Some((command.to_string(), child)),
+            Err(e) => {
+                let event = format!("Failed to run {}: {}", command, e);
+                eventlog::report(signals, config, &event);
+                None
+            }
+        }
+    }
+
+    // Tells about a sleep command that failed, once it's done
+    fn check_sleep_command(
+        signals: &Signals,
+        config: &Config,
+        running: &mut Option<(String, Child)>,
+    ) {
+        let Some((command, child)) = running.as_mut() else {
+            return;
+        };
+        let event = match child.try_wait() {
+            Ok(None) => return,
+            Ok(Some(status)) if status.success() => None,
+            Ok(Some(status)) => Some(format!("{} exited with {}", command, status)),
+            Err(e) => Some(format!("Failed to run {}: {}", command, e)),
+        };
+        if let Some(event) = event {
+            eventlog::report(signals, config, &event);
+        }
+        *running = None;
+    }
+
     pub fn status_monitor(
         signals: &Signals,
-        m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-        sleep_status: Sender<(String, PCStatus)>,
+        config: &Config,
+        auth: &Authenticator,
+        pc_table: &PcTable,
+        sleep_status: Sender<(NodeId, PCStatus)>,
     ) {
-        let socket = UdpSocket::bind(MONITOR_ADDR).expect("Failed to bind monitor socket");
+        let socket = Socket::new(
+            netif::bind(config, config.monitor_addr()).expect("Failed to bind monitor socket"),
+            config.cluster_id,
+            auth,
+        );
         socket
-            .set_read_timeout(Some(WAIT_DELAY))
+            .set_read_timeout(Some(config.wait_delay()))
             .expect("Failed to set monitor socket read timeout");
         let mut manager_last_seen = Instant::now();
+        let mut last_sleep_id = None;
+        let mut sleep_command_running = None;
 
         while signals.running() {
             if signals.is_manager() {
-                let pc_map = m_pc_map.lock().unwrap();
-                let pcs = pc_map
+                // Probing takes a while, the table stays free meanwhile
+                let pcs = pc_table.snapshot();
+                let probes = pcs
                     .iter()
-                    .map(|(k, v)| (k, v.get_ip(), v.get_status()))
+                    .map(|v| (v.get_id(), v.get_ip(), v.get_status()))
                     .collect();
 
-                listen_for_clients(&signals, &socket, pcs, &sleep_status);
+                listen_for_clients(signals, config, &socket, probes, &sleep_status);
             } else {
-                let mut buf = [0; BUFFER_SIZE];
-                match socket.recv_from(&mut buf) {
-                    Ok((amt, src)) => {
-                        if check_packet(&buf[..amt], SsrPacket).is_err() {
-                            continue;
-                        }
-                        let ssra = make_header(SsrAckPacket, 0);
-                        socket.send_to(&ssra, src).unwrap();
+                match socket.recv_from() {
+                    Ok((Message::Status, src)) => {
+                        socket.send_to(&Message::StatusAck, src).unwrap();
                         manager_last_seen = Instant::now();
                     }
+                    Ok((Message::Sleep { id, action }, src)) => {
+                        let command = sleep_command(config, auth, pc_table, action, &src.ip());
+                        let ack = Message::SleepAck {
+                            id,
+                            accepted: command.is_ok(),
+                        };
+                        socket.send_to(&ack, src).unwrap();
+                        match command {
+                            // A retry of a request we already acted on
+                            Ok(_) if last_sleep_id == Some(id) => {}
+                            Ok(command) => {
+                                last_sleep_id = Some(id);
+                                sleep_command_running =
+                                    run_sleep_command(signals, config, action, command);
+                            }
+                            Err(reason) => eventlog::report(
+                                signals,
+                                config,
+                                &format!("Refused to {}: {}", action, reason),
+                            ),
+                        }
+                    }
+                    Ok(_) => {}
                     Err(_) => {
-                        if signals.manager_found() && manager_last_seen.elapsed() >= MANAGER_TIMEOUT
+                        if signals.manager_found()
+                            && manager_last_seen.elapsed() >= config.manager_timeout()
                         {
-                            let mut pc_map = m_pc_map.lock().unwrap();
-
                             // find the manager and then remove it
-                            pc_map.retain(|_, v| !v.is_manager());
+                            pc_table.retain(|v| !v.is_manager());
                             signals.lost_manager();
-                            signals.send_update();
                         }
                     }
                 }
             }
-            std::thread::sleep(CHECK_DELAY);
+            check_sleep_command(signals, config, &mut sleep_command_running);
+            signals.pause(config.check_delay());
         }
     }
 }
 
 pub mod exit {
-    use crate::{
-        addrs::{EXIT_ADDR, EXIT_BROADCAST_ADDR},
-        packets::{PacketType::SsePacket, HEADER_SIZE},
-    };
-
     use super::*;
 
-    pub fn exit_monitor(signals: &Signals, exit_tx: Sender<String>) {
-        let socket = UdpSocket::bind(EXIT_ADDR).unwrap();
-        socket.set_read_timeout(Some(WAIT_DELAY)).unwrap();
+    pub fn exit_monitor(
+        signals: &Signals,
+        config: &Config,
+        auth: &Authenticator,
+        exit_tx: Sender<NodeId>,
+    ) {
+        let socket = Socket::new(
+            netif::bind(config, config.exit_addr()).unwrap(),
+            config.cluster_id,
+            auth,
+        );
+        socket.set_read_timeout(Some(config.wait_delay())).unwrap();
+        netif::join(&socket, config, config.exit_broadcast_addr()).unwrap();
 
         while signals.running() {
-            let mut buf = [0; BUFFER_SIZE];
-            match socket.recv_from(&mut buf) {
-                Ok((amt, _src)) => {
-                    if check_packet(&buf[..amt], SsePacket).is_err() {
-                        continue; // Ignore invalid packets
-                    }
-
-                    let hostname = buf[HEADER_SIZE..amt]
-                        .iter()
-                        .map(|&c| c as char)
-                        .collect::<String>();
-
-                    exit_tx.send(hostname).unwrap();
-                }
-                Err(_) => {}
+            if let Ok((Message::Exit { id }, _src)) = socket.recv_from() {
+                exit_tx.send(id).unwrap();
             }
         }
         // Send the exit signal to other pcs
-        socket.set_broadcast(true).unwrap();
-        let exit_packet = make_header(SsePacket, 0);
-        socket.send_to(&exit_packet, EXIT_BROADCAST_ADDR).unwrap();
+        let exit_packet = Message::Exit { id: config.node_id };
+        socket
+            .send_to(&exit_packet, config.exit_broadcast_addr())
+            .unwrap();
     }
 }
@@ -1,21 +1,30 @@
 use std::{
-    collections::HashMap,
-    net::UdpSocket,
-    sync::{mpsc::Receiver, Mutex},
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use gethostname::gethostname;
-use local_ip_address::local_ip;
 
 use crate::{
-    addrs::{REPLICATION_ADDR, REPLICATION_BROADCAST_ADDR},
-    delays::CHECK_DELAY,
-    packets::{HEADER_SIZE, get_packet_length, make_header, PacketType::SsrepPacket, BUFFER_SIZE},
-    pcinfo::{PCInfo, PCStatus},
+    config::Config,
+    eventlog,
+    events::Event,
+    netif,
+    nodeid::NodeId,
+    packets::{fragment, Authenticator, Message, Reassembler, Snapshot, Socket},
+    pcinfo::{PCInfo, PCStatus, WakeRequest},
+    pctable::PcTable,
+    schedule::Schedule,
     signals::Signals,
+    state::{self, State},
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum UpdateType {
     Add = 0x01,
@@ -35,125 +44,530 @@ impl std::convert::TryFrom<u8> for UpdateType {
     }
 }
 
-fn receive_update(buf: &[u8]) -> Result<(HashMap<String, PCInfo>, u32), ()> {
-    let mut num_entries = get_packet_length(buf);
-    let msg = buf[HEADER_SIZE..].to_vec();
-    let table_version = u32::from_be_bytes(msg[..4].try_into().unwrap());
-    let mut bytes_used: usize = 4;
-    let mut pc_map = HashMap::new();
-    while num_entries > 0 {
-        match PCInfo::from_bytes(&msg[bytes_used..]) {
-            Ok((pc_info, used)) => {
-                pc_map.insert(pc_info.get_name().clone(), pc_info);
-                bytes_used += used;
-                num_entries -= 1;
+fn apply_update(rb_pc_map: &mut HashMap<NodeId, PCInfo>, update_type: UpdateType, pc_info: PCInfo) {
+    match update_type {
+        UpdateType::Add | UpdateType::Change => {
+            rb_pc_map.insert(*pc_info.get_id(), pc_info);
+        }
+        UpdateType::Remove => {
+            rb_pc_map.remove(pc_info.get_id());
+        }
+    }
+}
+
+fn apply_delta(
+    rb_pc_map: &mut HashMap<NodeId, PCInfo>,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+    delta: Message,
+) {
+    match delta {
+        Message::ReplicationDelta {
+            update_type,
+            pc_info,
+            ..
+        } => apply_update(rb_pc_map, update_type, pc_info),
+        Message::ScheduleDelta {
+            update_type,
+            schedule,
+            ..
+        } => {
+            let mut schedules = m_schedules.lock().unwrap();
+            match update_type {
+                UpdateType::Add | UpdateType::Change => {
+                    schedules.insert(schedule.name.clone(), schedule);
+                }
+                UpdateType::Remove => {
+                    schedules.remove(&schedule.name);
+                }
             }
-            Err(_) => return Err(()),
         }
+        _ => {}
+    }
+}
+
+// The table version a delta produces
+fn delta_version(delta: &Message) -> Option<u32> {
+    match delta {
+        Message::ReplicationDelta { table_version, .. }
+        | Message::ScheduleDelta { table_version, .. } => Some(*table_version),
+        _ => None,
     }
-    Ok((pc_map, table_version))
 }
 
-pub fn serialize_pc_map(pc_map: &HashMap<String, PCInfo>) -> Vec<u8> {
-    let mut buf = Vec::new();
-    for pc_info in pc_map.values() {
-        buf.extend(pc_info.to_bytes());
+fn take_snapshot(
+    rb_pc_map: &HashMap<NodeId, PCInfo>,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+) -> Snapshot {
+    Snapshot {
+        pcs: rb_pc_map.values().cloned().collect(),
+        schedules: m_schedules.lock().unwrap().values().cloned().collect(),
+    }
+}
+
+// The machines that can vote in an election, asleep or not. Only leaving
+// the table takes one off, never a timeout.
+fn members(rb_pc_map: &HashMap<NodeId, PCInfo>) -> u32 {
+    rb_pc_map.len() as u32
+}
+
+fn send_snapshot(socket: &Socket, table_version: u32, snapshot: &Snapshot, addr: SocketAddr) {
+    // Split in as many packets as needed
+    for packet in fragment(table_version, snapshot) {
+        socket.send_to(&packet, addr).unwrap();
+    }
+}
+
+// How many past deltas the manager keeps around for retransmissions,
+// backups further behind than this get a snapshot instead
+const DELTA_LOG_SIZE: usize = 64;
+const MAX_BACKOFF_FACTOR: u32 = 32;
+
+struct Backup {
+    acked: u32,
+    backoff: Duration,
+    next_retry: Instant,
+}
+
+// What each backup has acknowledged, kept by the manager so it can
+// retransmit to whoever falls behind, backing off while they don't answer
+struct AckTracker {
+    backups: HashMap<IpAddr, Backup>,
+    log: VecDeque<Message>,
+    base_backoff: Duration,
+}
+
+impl AckTracker {
+    fn new(base_backoff: Duration) -> Self {
+        Self {
+            backups: HashMap::new(),
+            log: VecDeque::new(),
+            base_backoff,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.backups.clear();
+        self.log.clear();
+    }
+
+    fn sent(&mut self, delta: Message) {
+        if self.log.len() == DELTA_LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(delta);
+        // Give everyone a chance to ack before retransmitting
+        let next_retry = Instant::now() + self.base_backoff;
+        for backup in self.backups.values_mut() {
+            backup.next_retry = backup.next_retry.max(next_retry);
+        }
+    }
+
+    fn acked(&mut self, ip: IpAddr, table_version: u32) {
+        let base_backoff = self.base_backoff;
+        let backup = self.backups.entry(ip).or_insert(Backup {
+            acked: 0,
+            backoff: base_backoff,
+            next_retry: Instant::now(),
+        });
+        if table_version > backup.acked {
+            backup.acked = table_version;
+            backup.backoff = base_backoff;
+            backup.next_retry = Instant::now() + base_backoff;
+        }
+    }
+
+    // Resends whatever each online backup is missing, either the deltas
+    // after its last ack or the whole table
+    fn retransmit(
+        &mut self,
+        socket: &Socket,
+        table_version: u32,
+        rb_pc_map: &HashMap<NodeId, PCInfo>,
+        m_schedules: &Mutex<HashMap<String, Schedule>>,
+        our_id: &NodeId,
+        config: &Config,
+    ) {
+        let now = Instant::now();
+        for pc_info in rb_pc_map.values() {
+            if pc_info.get_id() == our_id || !pc_info.is_online() {
+                continue;
+            }
+            let backup = self.backups.entry(*pc_info.get_ip()).or_insert(Backup {
+                acked: 0,
+                backoff: self.base_backoff,
+                next_retry: now + self.base_backoff,
+            });
+            if backup.acked >= table_version || now < backup.next_retry {
+                continue;
+            }
+
+            let addr = config.peer_addr(*pc_info.get_ip(), config.replication_port);
+            let missing = self
+                .log
+                .iter()
+                .filter(|delta| delta_version(delta).is_some_and(|v| v > backup.acked));
+            if backup.acked > 0 && self.log.len() + backup.acked as usize >= table_version as usize
+            {
+                for delta in missing {
+                    socket.send_to(delta, addr).unwrap();
+                }
+            } else {
+                let snapshot = take_snapshot(rb_pc_map, m_schedules);
+                send_snapshot(socket, table_version, &snapshot, addr);
+            }
+
+            backup.backoff = (backup.backoff * 2).min(self.base_backoff * MAX_BACKOFF_FACTOR);
+            backup.next_retry = now + backup.backoff;
+        }
+    }
+
+    // How many backups have a version, not counting the manager
+    fn acked_by(&self, table_version: u32) -> usize {
+        self.backups
+            .values()
+            .filter(|backup| backup.acked >= table_version)
+            .count()
     }
-    buf
+
+    // Last version acknowledged by each backup
+    fn report(&self, rb_pc_map: &HashMap<NodeId, PCInfo>) -> HashMap<NodeId, u32> {
+        rb_pc_map
+            .values()
+            .filter_map(|pc_info| {
+                self.backups
+                    .get(pc_info.get_ip())
+                    .map(|backup| (*pc_info.get_id(), backup.acked))
+            })
+            .collect()
+    }
+}
+
+// Applies a change to the manager's backup table and sends it out
+fn replicate(
+    signals: &Signals,
+    config: &Config,
+    socket: &Socket,
+    tracker: &mut AckTracker,
+    rb_pc_map: &mut HashMap<NodeId, PCInfo>,
+    update_type: UpdateType,
+    pc_info: PCInfo,
+) {
+    let curr_table_version = signals.update_table_version();
+    apply_update(rb_pc_map, update_type, pc_info.clone());
+
+    // Only the change goes out, tagged with the version it produces
+    let delta = Message::ReplicationDelta {
+        table_version: curr_table_version,
+        update_type,
+        pc_info,
+    };
+    send_delta(config, socket, tracker, delta);
+}
+
+// The scheduler already changed the shared schedules, only the
+// version and the backups are left. Returns the version it produced.
+fn replicate_schedule(
+    signals: &Signals,
+    config: &Config,
+    socket: &Socket,
+    tracker: &mut AckTracker,
+    update_type: UpdateType,
+    schedule: Schedule,
+) -> u32 {
+    let table_version = signals.update_table_version();
+    let delta = Message::ScheduleDelta {
+        table_version,
+        update_type,
+        schedule,
+    };
+    send_delta(config, socket, tracker, delta);
+    table_version
 }
 
+// Wakes up for the schedules that ran once a majority, ourselves included,
+// holds the version with their new last run. Any manager elected after
+// that has it, since nobody votes for a candidate with an older table.
+fn release_runs(
+    signals: &Signals,
+    config: &Config,
+    tracker: &AckTracker,
+    pending_runs: &mut Vec<(u32, Schedule)>,
+    wakeups: &Sender<WakeRequest>,
+) {
+    let majority = signals.members().max(1) as usize / 2 + 1;
+    pending_runs.retain(|(table_version, schedule)| {
+        if tracker.acked_by(*table_version) + 1 < majority {
+            return true;
+        }
+        eventlog::report(
+            signals,
+            config,
+            &format!("Schedule {} waking up {}", schedule.name, schedule.request),
+        );
+        wakeups.send(schedule.request.clone()).unwrap();
+        false
+    });
+}
+
+fn send_delta(config: &Config, socket: &Socket, tracker: &mut AckTracker, delta: Message) {
+    socket
+        .send_to(&delta, config.replication_broadcast_addr())
+        .unwrap();
+    tracker.sent(delta);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn initialize(
     signals: &Signals,
-    m_pc_map: &Mutex<HashMap<String, PCInfo>>,
-    updates: Receiver<(UpdateType, PCInfo)>,
+    config: &Config,
+    auth: &Authenticator,
+    pc_table: &PcTable,
+    m_acks: &Mutex<HashMap<NodeId, u32>>,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+    saved_state: Option<State>,
+    updates: &Receiver<(UpdateType, PCInfo)>,
+    schedule_updates: &Receiver<(UpdateType, Schedule)>,
+    deposed_rx: &Receiver<IpAddr>,
+    wakeups: Sender<WakeRequest>,
 ) {
-    let socket = UdpSocket::bind(REPLICATION_ADDR).unwrap();
-    socket.set_nonblocking(true).unwrap();
-    socket.set_broadcast(true).unwrap();
-    let mut rb_pc_map = m_pc_map.lock().unwrap().clone();
+    let socket = Socket::new(
+        netif::bind(config, config.replication_addr()).unwrap(),
+        config.cluster_id,
+        auth,
+    );
+    socket.set_read_timeout(Some(config.check_delay())).unwrap();
+    netif::join(&socket, config, config.replication_broadcast_addr()).unwrap();
+    let mut rb_pc_map = pc_table
+        .snapshot()
+        .iter()
+        .map(|pc_info| (*pc_info.get_id(), pc_info.clone()))
+        .collect::<HashMap<_, _>>();
+    if let Some(saved_state) = saved_state {
+        for pc_info in saved_state.snapshot.pcs {
+            rb_pc_map.insert(*pc_info.get_id(), pc_info);
+        }
+        let mut schedules = m_schedules.lock().unwrap();
+        for schedule in saved_state.snapshot.schedules {
+            schedules.insert(schedule.name.clone(), schedule);
+        }
+    }
+    let mut saved_version = signals.current_table_version();
     let mut was_manager = signals.is_manager();
+    // The manager drains the socket between updates, backups wait on it
+    socket.set_nonblocking(was_manager).unwrap();
+    let mut reassembler = Reassembler::new();
+    // Deltas only make sense on top of a snapshot from the current manager
+    let mut synced = false;
+    let mut last_request: Option<Instant> = None;
+    let mut tracker = AckTracker::new(config.wait_delay());
+    // Schedules that ran, waiting for a majority before waking anyone up
+    let mut pending_runs: Vec<(u32, Schedule)> = Vec::new();
 
     // Our own PCInfo
+    let our_id = config.node_id;
     let our_hostname = gethostname().into_string().unwrap();
-    let our_mac = mac_address::get_mac_address()
-        .unwrap()
-        .expect("Failed to get MAC address");
-    let our_ip = local_ip().expect("Failed to get local IP address");
+    let our_mac = config.local.mac;
+    let our_ip = config.local.ip;
     // if we're the manager, when people net
     let our_status = PCStatus::Online;
-    let ourselves = PCInfo::new(our_hostname.clone(), our_mac, our_ip, our_status, false);
-    rb_pc_map.insert(our_hostname.clone(), ourselves);
+    let ourselves = PCInfo::new(our_id, our_hostname, our_mac, our_ip, our_status, false);
+    rb_pc_map.insert(our_id, ourselves);
+    signals.set_members(members(&rb_pc_map));
 
     while signals.running() {
+        if signals.current_table_version() != saved_version {
+            saved_version = signals.current_table_version();
+            signals.set_members(members(&rb_pc_map));
+            if config.persist_state {
+                let snapshot = take_snapshot(&rb_pc_map, m_schedules);
+                if let Err(e) = state::save(&config.state_file, saved_version, &snapshot) {
+                    let event = format!("Failed to save {}: {}", config.state_file.display(), e);
+                    eventlog::report(signals, config, &event);
+                }
+            }
+        }
+
+        // We lost a manager conflict, the winner merges our table
+        if let Ok(winner) = deposed_rx.try_recv() {
+            let addr = config.peer_addr(winner, config.replication_port);
+            let snapshot = take_snapshot(&rb_pc_map, m_schedules);
+            send_snapshot(&socket, signals.current_table_version(), &snapshot, addr);
+        }
+
         if was_manager != signals.is_manager() {
             was_manager = signals.is_manager();
+            socket.set_nonblocking(was_manager).unwrap();
+            tracker.clear();
+            m_acks.lock().unwrap().clear();
+            // Held runs might not have reached the next manager
+            for (_, schedule) in pending_runs.drain(..) {
+                let event = format!("Schedule {} may not have run", schedule.name);
+                eventlog::report(signals, config, &event);
+            }
             if was_manager {
-                let mut pc_map = m_pc_map.lock().unwrap();
-                pc_map.clear();
                 for pc_info in rb_pc_map.values_mut() {
-                    if *pc_info.get_name() == our_hostname {
-                        // We don't want to add ourselves to the map
-                        continue;
-                    }
                     if *pc_info.get_is_manager() {
                         pc_info.set_is_manager(false);
                     }
-                    pc_map.insert(pc_info.get_name().clone(), pc_info.clone());
-                }
-                if !pc_map.is_empty() {
-                    signals.send_update();
                 }
+                pc_table.replace(
+                    rb_pc_map
+                        .values()
+                        // We don't want to add ourselves to the map
+                        .filter(|pc_info| *pc_info.get_id() != our_id)
+                        .cloned(),
+                );
             } else {
                 // We are no longer the manager
-                let mut pc_map = m_pc_map.lock().unwrap();
+                synced = false;
                 // remove everything but the manager, if there is any
-                pc_map.retain(|_, v| v.is_manager());
-                if !pc_map.is_empty() {
-                    signals.send_update();
-                }
+                pc_table.retain(|v| v.is_manager());
             }
         }
 
         if signals.is_manager() {
-            match updates.try_recv() {
-                Ok((update_type, pc_info)) => {
-                    // Update backup table
-                    let curr_table_version = signals.update_table_version();
-                    match update_type {
-                        UpdateType::Add => {
-                            rb_pc_map.insert(pc_info.get_name().clone(), pc_info);
-                        }
-                        UpdateType::Remove => {
-                            rb_pc_map.remove(pc_info.get_name());
+            let mut acks_changed = false;
+            while let Ok((message, src)) = socket.recv_from() {
+                match message {
+                    Message::ReplicationAck { table_version } => {
+                        tracker.acked(src.ip(), table_version);
+                        acks_changed = true;
+                    }
+                    // Backups that fell behind ask us for the whole table
+                    Message::SnapshotRequest { .. } => {
+                        let snapshot = take_snapshot(&rb_pc_map, m_schedules);
+                        send_snapshot(&socket, signals.current_table_version(), &snapshot, src);
+                    }
+                    // The table of a manager we deposed, keep what we don't know yet
+                    message @ Message::Replication { .. } => {
+                        let Some((_, snapshot)) = reassembler.add(src.ip(), message) else {
+                            continue;
+                        };
+                        for mut pc_info in snapshot.pcs {
+                            if rb_pc_map.contains_key(pc_info.get_id()) {
+                                continue;
+                            }
+                            pc_info.set_is_manager(false);
+                            pc_table.add(pc_info.clone());
+                            replicate(
+                                signals,
+                                config,
+                                &socket,
+                                &mut tracker,
+                                &mut rb_pc_map,
+                                UpdateType::Add,
+                                pc_info,
+                            );
                         }
-                        UpdateType::Change => {
-                            rb_pc_map.insert(pc_info.get_name().clone(), pc_info);
+                        for schedule in snapshot.schedules {
+                            let mut schedules = m_schedules.lock().unwrap();
+                            if schedules.contains_key(&schedule.name) {
+                                continue;
+                            }
+                            schedules.insert(schedule.name.clone(), schedule.clone());
+                            drop(schedules);
+                            replicate_schedule(
+                                signals,
+                                config,
+                                &socket,
+                                &mut tracker,
+                                UpdateType::Add,
+                                schedule,
+                            );
                         }
                     }
+                    _ => {}
+                }
+            }
+            if acks_changed {
+                *m_acks.lock().unwrap() = tracker.report(&rb_pc_map);
+            }
+            tracker.retransmit(
+                &socket,
+                signals.current_table_version(),
+                &rb_pc_map,
+                m_schedules,
+                &our_id,
+                config,
+            );
 
-                    // Serialize the PC map
-                    let mut buf = Vec::new();
-                    buf.extend(curr_table_version.to_be_bytes().iter());
-                    buf.extend(serialize_pc_map(&rb_pc_map).iter());
-                    let header = make_header(SsrepPacket, rb_pc_map.len());
-                    let packet = [header.to_vec(), buf].concat();
-
-                    // Send the update
-                    socket.send_to(&packet, REPLICATION_BROADCAST_ADDR).unwrap();
+            if let Ok((update_type, schedule)) = schedule_updates.try_recv() {
+                // The scheduler only changes a schedule when it runs it
+                let ran = (update_type == UpdateType::Change).then(|| schedule.clone());
+                let table_version = replicate_schedule(
+                    signals,
+                    config,
+                    &socket,
+                    &mut tracker,
+                    update_type,
+                    schedule,
+                );
+                if let Some(schedule) = ran {
+                    pending_runs.push((table_version, schedule));
                 }
-                Err(_) => std::thread::sleep(CHECK_DELAY),
+            }
+            release_runs(signals, config, &tracker, &mut pending_runs, &wakeups);
+
+            if let Ok((update_type, pc_info)) = updates.recv_timeout(config.check_delay()) {
+                replicate(
+                    signals,
+                    config,
+                    &socket,
+                    &mut tracker,
+                    &mut rb_pc_map,
+                    update_type,
+                    pc_info,
+                );
             }
         } else {
-            let mut buf = [0; BUFFER_SIZE];
-            match socket.recv_from(&mut buf) {
-                Ok((amt, _src)) => match receive_update(&buf[..amt]) {
-                    Ok((pc_map, table_version)) => {
-                        rb_pc_map = pc_map;
-                        signals.overwrite_table_version(table_version);
-                    }
-                    Err(_) => continue,
-                },
-                Err(_) => std::thread::sleep(CHECK_DELAY),
+            // Times out every check_delay to look at our role again
+            let Ok((message, src)) = socket.recv_from() else {
+                continue;
+            };
+            let Some(table_version) = delta_version(&message) else {
+                if let Some((table_version, snapshot)) = reassembler.add(src.ip(), message) {
+                    rb_pc_map = snapshot
+                        .pcs
+                        .into_iter()
+                        .map(|pc_info| (*pc_info.get_id(), pc_info))
+                        .collect();
+                    *m_schedules.lock().unwrap() = snapshot
+                        .schedules
+                        .into_iter()
+                        .map(|schedule| (schedule.name.clone(), schedule))
+                        .collect();
+                    signals.overwrite_table_version(table_version);
+                    signals.publish(Event::ReplicationApplied { table_version });
+                    synced = true;
+                    socket
+                        .send_to(&Message::ReplicationAck { table_version }, src)
+                        .unwrap();
+                }
+                continue;
+            };
+            let current = signals.current_table_version();
+            if synced && table_version <= current + 1 {
+                if table_version == current + 1 {
+                    apply_delta(&mut rb_pc_map, m_schedules, message);
+                    signals.overwrite_table_version(table_version);
+                    signals.publish(Event::ReplicationApplied { table_version });
+                }
+                // Acknowledge retransmissions too, our ack may have been lost
+                let ack = Message::ReplicationAck {
+                    table_version: signals.current_table_version(),
+                };
+                socket.send_to(&ack, src).unwrap();
+            } else {
+                // We missed something, catch up with a snapshot
+                let requested_recently =
+                    last_request.is_some_and(|t| t.elapsed() < config.wait_delay());
+                if !requested_recently {
+                    let request = Message::SnapshotRequest {
+                        table_version: current,
+                    };
+                    socket.send_to(&request, src).unwrap();
+                    last_request = Some(Instant::now());
+                }
             }
         }
     }
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex,
+    },
+};
+
+use crate::{
+    config::Config,
+    eventlog,
+    node::NodeError,
+    schedule::{current_minute, Schedule, ScheduleEdit},
+    signals::Signals,
+};
+
+use super::replication::UpdateType;
+
+fn edit(
+    signals: &Signals,
+    config: &Config,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+    edit: ScheduleEdit,
+    rb_schedule_tx: &Sender<(UpdateType, Schedule)>,
+) {
+    let mut schedules = m_schedules.lock().unwrap();
+    // Node checked the edit already, but a replicated one may have come in
+    let event = match edit {
+        ScheduleEdit::Add(mut schedule) => {
+            if schedules.contains_key(&schedule.name) {
+                NodeError::ScheduleExists(schedule.name).to_string()
+            } else {
+                // Starting from the next run
+                schedule.last_run = current_minute();
+                schedules.insert(schedule.name.clone(), schedule.clone());
+                let event = format!("Added schedule {}", schedule.name);
+                rb_schedule_tx.send((UpdateType::Add, schedule)).unwrap();
+                event
+            }
+        }
+        ScheduleEdit::Remove(name) => match schedules.remove(&name) {
+            Some(schedule) => {
+                rb_schedule_tx.send((UpdateType::Remove, schedule)).unwrap();
+                format!("Removed schedule {}", name)
+            }
+            None => NodeError::ScheduleNotFound(name).to_string(),
+        },
+    };
+    drop(schedules);
+    eventlog::report(signals, config, &event);
+}
+
+// Runs every schedule due since its last run, once even if a failover
+// made it miss several
+fn run_due(
+    config: &Config,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+    rb_schedule_tx: &Sender<(UpdateType, Schedule)>,
+) {
+    let now = current_minute();
+    let grace = config.schedule_grace().as_secs() / 60;
+    let mut schedules = m_schedules.lock().unwrap();
+    for schedule in schedules.values_mut() {
+        if schedule.last_run >= now {
+            continue;
+        }
+        let from = (schedule.last_run + 1).max(now.saturating_sub(grace));
+        if !(from..=now).any(|minute| schedule.cron.matches(minute)) {
+            continue;
+        }
+        schedule.last_run = now;
+        // Replication wakes the PCs up once a majority has the new last
+        // run, so that whichever manager comes next doesn't run it again
+        rb_schedule_tx
+            .send((UpdateType::Change, schedule.clone()))
+            .unwrap();
+    }
+}
+
+pub fn run(
+    signals: &Signals,
+    config: &Config,
+    m_schedules: &Mutex<HashMap<String, Schedule>>,
+    edits: &Receiver<ScheduleEdit>,
+    rb_schedule_tx: Sender<(UpdateType, Schedule)>,
+) {
+    while signals.running() {
+        match edits.recv_timeout(config.check_delay()) {
+            Ok(schedule_edit) => edit(signals, config, m_schedules, schedule_edit, &rb_schedule_tx),
+            Err(_) => {
+                // Only the manager runs the schedules, the others keep
+                // the replicated copy in case they take over
+                if signals.is_manager() {
+                    run_due(config, m_schedules, &rb_schedule_tx);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{config::Config, delays, eventlog, signals::Signals};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A subservice crashing more often than this within a minute is given up
+// on, along with the node
+const MAX_CRASHES: usize = 3;
+const CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Running,
+    // Running again after crashing, last_crash is the panic message
+    Restarted { restarts: u32, last_crash: String },
+    // Crashed too often, which stopped the node
+    Failed(String),
+    Stopped,
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Health::Running => write!(f, "running"),
+            Health::Restarted {
+                restarts,
+                last_crash,
+            } => write!(f, "running, restarted {} times: {}", restarts, last_crash),
+            Health::Failed(reason) => write!(f, "failed: {}", reason),
+            Health::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+// What a crashed subservice held is still good to start it again
+pub fn reclaim<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+type Run = Arc<dyn Fn() + Send + Sync>;
+
+struct Subservice {
+    name: &'static str,
+    // Kept here so that the channels it holds outlive its thread, and
+    // nobody sends to a closed channel while the node stops
+    _run: Run,
+    health: Arc<Mutex<Health>>,
+}
+
+// Runs every subservice on its own thread, starting the ones that panic
+// again and stopping the node when one keeps crashing
+pub struct Supervisor {
+    signals: Arc<Signals>,
+    config: Arc<Config>,
+    subservices: Vec<Subservice>,
+    done_tx: Sender<&'static str>,
+    done_rx: Mutex<Receiver<&'static str>>,
+}
+
+fn supervise(signals: &Signals, config: &Config, name: &str, run: &Run, health: &Mutex<Health>) {
+    let mut crashes = VecDeque::new();
+    let mut restarts = 0;
+    while signals.running() {
+        let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| run())) else {
+            break;
+        };
+        if !signals.running() {
+            break;
+        }
+        let reason = panic_message(&*panic);
+        crashes.retain(|crash: &Instant| crash.elapsed() < CRASH_WINDOW);
+        crashes.push_back(Instant::now());
+        if crashes.len() > MAX_CRASHES {
+            let event = format!("{} keeps crashing, stopping: {}", name, reason);
+            eventlog::report(signals, config, &event);
+            *reclaim(health) = Health::Failed(reason);
+            signals.exit();
+            return;
+        }
+        restarts += 1;
+        let event = format!("{} crashed, restarting it: {}", name, reason);
+        eventlog::report(signals, config, &event);
+        *reclaim(health) = Health::Restarted {
+            restarts,
+            last_crash: reason,
+        };
+        signals.pause(delays::RESTART_DELAY);
+    }
+    *reclaim(health) = Health::Stopped;
+}
+
+impl Supervisor {
+    pub fn new(signals: Arc<Signals>, config: Arc<Config>) -> Self {
+        let (done_tx, done_rx) = channel();
+        Self {
+            signals,
+            config,
+            subservices: Vec::new(),
+            done_tx,
+            done_rx: Mutex::new(done_rx),
+        }
+    }
+
+    pub fn spawn(&mut self, name: &'static str, run: impl Fn() + Send + Sync + 'static) {
+        let run: Run = Arc::new(run);
+        let health = Arc::new(Mutex::new(Health::Running));
+
+        let sigs = self.signals.clone();
+        let cfg = self.config.clone();
+        let rn = run.clone();
+        let hlth = health.clone();
+        let done_tx = self.done_tx.clone();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                supervise(&sigs, &cfg, name, &rn, &hlth);
+                let _ = done_tx.send(name);
+            })
+            .expect("Failed to start a subservice");
+
+        self.subservices.push(Subservice {
+            name,
+            _run: run,
+            health,
+        });
+    }
+
+    pub fn health(&self) -> Vec<(&'static str, Health)> {
+        self.subservices
+            .iter()
+            .map(|subservice| (subservice.name, reclaim(&subservice.health).clone()))
+            .collect()
+    }
+
+    fn running(&self) -> Vec<&'static str> {
+        self.health()
+            .into_iter()
+            .filter(|(_, health)| !matches!(health, Health::Stopped | Health::Failed(_)))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // Waits until the node stops, then for its subservices to finish, but
+    // no longer than the timeout
+    pub fn join(&self, timeout: Duration) {
+        self.signals.wait_for_exit();
+        let deadline = Instant::now() + timeout;
+        let done_rx = reclaim(&self.done_rx);
+        loop {
+            let running = self.running();
+            if running.is_empty() {
+                return;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(left) {
+                let event = format!("Gave up waiting for {} to stop", running.join(", "));
+                eventlog::report(&self.signals, &self.config, &event);
+                return;
+            }
+        }
+    }
+}
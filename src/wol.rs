@@ -0,0 +1,47 @@
+use crate::{
+    config::Config,
+    netif,
+    packets::make_wakeup_packet,
+    pcinfo::{PCInfo, WakeTarget, WakeVia},
+};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+fn wake_addr(config: &Config, wake_target: &WakeTarget) -> SocketAddr {
+    let port = wake_target.port.unwrap_or(config.wakeup_port);
+    match wake_target.via {
+        // Raw Ethernet falls back to a local broadcast
+        WakeVia::Local | WakeVia::Ethernet => SocketAddr::new(config.wakeup_addr().ip(), port),
+        WakeVia::Directed(ip) => SocketAddr::new(ip.into(), port),
+        WakeVia::Relay(ip) => config.peer_addr(ip, port),
+    }
+}
+
+// Sends the magic packet to a PC the way its wake target says, returning
+// the way it actually went out
+pub fn send_wakeup(
+    config: &Config,
+    socket: &UdpSocket,
+    pc_info: &PCInfo,
+) -> std::io::Result<WakeTarget> {
+    let wakeup_packet = make_wakeup_packet(pc_info.get_mac(), pc_info.get_secure_on());
+    let mut wake_target = *pc_info.get_wake_target();
+    if wake_target.via == WakeVia::Ethernet {
+        match netif::send_ethernet(config, pc_info.get_mac(), &wakeup_packet) {
+            Ok(()) => return Ok(wake_target),
+            // Without CAP_NET_RAW or off Linux, the caller can tell from
+            // the result
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::Unsupported
+                ) =>
+            {
+                wake_target.via = WakeVia::Local;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    socket.send_to(&wakeup_packet, wake_addr(config, &wake_target))?;
+    Ok(wake_target)
+}